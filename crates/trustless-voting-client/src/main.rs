@@ -1,7 +1,8 @@
 use aes_gcm::{
-    aead::{generic_array::GenericArray, Aead, NewAead},
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
     Aes256Gcm,
 };
+use hkdf::Hkdf;
 use rand::Rng;
 use secp256k1::{PublicKey, SecretKey};
 use sha2::Sha256;
@@ -15,6 +16,14 @@ use structopt::StructOpt;
 use tiny_keccak::{Hasher, Keccak};
 
 const KEY_PATH: &str = "key.bin";
+/// Version of the ballot format, authenticating the election and the sender, and encrypted with
+/// a key derived by HKDF-SHA256 from the shared secret.
+const BALLOT_VERSION: u8 = 3;
+/// HKDF info of the ballot key.
+const BALLOT_INFO: &[u8] = b"SgxVoting ballot";
+/// EIP-712 domain name and version of registrations.
+const DOMAIN_NAME: &str = "SgxVoting";
+const DOMAIN_VERSION: &str = "1";
 
 #[derive(StructOpt)]
 enum Args {
@@ -25,10 +34,32 @@ enum Args {
         contract: String,
         voting_id: String,
         mgr_addr: String,
+        /// signs EIP-712 typed data for the contract on this chain, instead of a personal message
+        #[structopt(long)]
+        chain_id: Option<u64>,
     },
+    /// encrypts a vote for the manager, bound to the election and the sender
     EncryptVote {
         mgr_key: String,
         vote: u32,
+        #[structopt(long, required_unless = "legacy")]
+        contract: Option<String>,
+        #[structopt(long, required_unless = "legacy")]
+        voting_id: Option<String>,
+        /// uses the legacy format, accepted only by managers allowing legacy ballots
+        #[structopt(long)]
+        legacy: bool,
+    },
+    /// verifies that the manager signed the receipt of a ballot, as in the X-Receipt-* headers
+    VerifyReceipt {
+        contract: String,
+        voting_id: String,
+        mgr_addr: String,
+        sender: String,
+        ballot_hash: String,
+        /// sequence number of the receipt, in hex
+        seq: String,
+        signature: String,
     },
 }
 
@@ -55,6 +86,83 @@ fn pub_key_to_ethaddr(pub_key: &PublicKey) -> [u8; 20] {
     result[12..].try_into().unwrap()
 }
 
+fn keccak256(chunks: &[&[u8]]) -> [u8; 32] {
+    let mut keccak = Keccak::v256();
+    let mut result = [0u8; 32];
+    for chunk in chunks {
+        keccak.update(chunk);
+    }
+    keccak.finalize(&mut result);
+    result
+}
+
+fn address_word(address: &[u8; 20]) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(address);
+    word
+}
+
+/// Hash of `SgxRegister(string votingId,address operator,address session)` typed data.
+fn register_typed_data(
+    chain_id: u64,
+    contract: &[u8; 20],
+    voting_id: &str,
+    mgr_addr: &[u8; 20],
+    session_addr: &[u8; 20],
+) -> [u8; 32] {
+    let mut chain_word = [0u8; 32];
+    chain_word[24..].copy_from_slice(&chain_id.to_be_bytes());
+    let domain = keccak256(&[
+        &keccak256(&[
+            b"EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)",
+        ]),
+        &keccak256(&[DOMAIN_NAME.as_bytes()]),
+        &keccak256(&[DOMAIN_VERSION.as_bytes()]),
+        &chain_word,
+        &address_word(contract),
+    ]);
+    let message = keccak256(&[
+        &keccak256(&[b"SgxRegister(string votingId,address operator,address session)"]),
+        &keccak256(&[voting_id.as_bytes()]),
+        &address_word(mgr_addr),
+        &address_word(session_addr),
+    ]);
+    keccak256(&[b"\x19\x01", &domain, &message])
+}
+
+/// Hash the manager signs in the receipt of the ballot of `sender`, accepted as record `seq`.
+fn receipt_hash(
+    contract: &[u8; 20],
+    voting_id: &str,
+    sender: &[u8; 20],
+    ballot_hash: &[u8; 32],
+    seq: u64,
+) -> [u8; 32] {
+    let mut seq_word = [0u8; 32];
+    seq_word[24..].copy_from_slice(&seq.to_be_bytes());
+    keccak256(&[
+        &keccak256(&[b"SgxVotingReceipt(address,bytes,address,bytes32,uint64)"]),
+        contract,
+        voting_id.as_bytes(),
+        sender,
+        ballot_hash,
+        &seq_word,
+    ])
+}
+
+/// Address of the signer of `hash`, given the signature as printed by the manager.
+fn recover_signer(hash: &[u8; 32], signature: &str) -> Result<[u8; 20], Box<dyn Error>> {
+    let mut sig_bytes = [0u8; 65];
+    hex::decode_to_slice(signature.trim_start_matches("0x"), &mut sig_bytes)?;
+    let sig = secp256k1::Signature::parse_slice(&sig_bytes[..64])?;
+    let rid = match sig_bytes[64] {
+        r if r >= 27 => secp256k1::RecoveryId::parse_rpc(r)?,
+        r => secp256k1::RecoveryId::parse(r)?,
+    };
+    let pkey = secp256k1::recover(&secp256k1::Message::parse(hash), &sig, &rid)?;
+    Ok(pub_key_to_ethaddr(&pkey))
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
     match Args::from_args() {
         Args::GenKey {} => {
@@ -70,23 +178,34 @@ fn main() -> Result<(), Box<dyn Error>> {
             contract,
             voting_id,
             mgr_addr,
+            chain_id,
         } => {
             let key = read_key()?;
             let pkey = PublicKey::from_secret_key(&key);
 
-            let msg = format!(
-                "\nSgxRegister\nContract: {} {}\nAddress: {}\nSession: {}",
-                contract,
-                voting_id,
-                mgr_addr,
-                hex::encode(pub_key_to_ethaddr(&pkey)),
-            );
-            let mut keccak = Keccak::v256();
-            let mut result = [0u8; 32];
-            keccak.update(b"\x19Ethereum Signed Message:\n".as_ref());
-            keccak.update(msg.len().to_string().as_ref());
-            keccak.update(msg.as_ref());
-            keccak.finalize(&mut result);
+            let result = match chain_id {
+                Some(chain_id) => register_typed_data(
+                    chain_id,
+                    &unhex_ethaddr(&contract)?,
+                    &voting_id,
+                    &unhex_ethaddr(&mgr_addr)?,
+                    &pub_key_to_ethaddr(&pkey),
+                ),
+                None => {
+                    let msg = format!(
+                        "\nSgxRegister\nContract: {} {}\nAddress: {}\nSession: {}",
+                        contract,
+                        voting_id,
+                        mgr_addr,
+                        hex::encode(pub_key_to_ethaddr(&pkey)),
+                    );
+                    keccak256(&[
+                        b"\x19Ethereum Signed Message:\n",
+                        msg.len().to_string().as_bytes(),
+                        msg.as_bytes(),
+                    ])
+                }
+            };
 
             let msg = secp256k1::Message::parse(&result);
 
@@ -99,7 +218,13 @@ fn main() -> Result<(), Box<dyn Error>> {
             println!("ADDR: {}", hex::encode(pub_key_to_ethaddr(&pkey)));
             println!("OK {}", sig_packed);
         }
-        Args::EncryptVote { mgr_key, vote } => {
+        Args::EncryptVote {
+            mgr_key,
+            vote,
+            contract,
+            voting_id,
+            legacy,
+        } => {
             let key = read_key()?;
 
             let mut mgr_key_bytes = [0u8; 65];
@@ -107,8 +232,8 @@ fn main() -> Result<(), Box<dyn Error>> {
             let mgr_key = PublicKey::parse(&mgr_key_bytes)?;
 
             let shared_sec = secp256k1::SharedSecret::<Sha256>::new(&mgr_key, &key)?;
-            let shared_key = GenericArray::from_slice(shared_sec.as_ref());
-            let cipher = Aes256Gcm::new(&shared_key);
+            let mut shared_key = [0u8; 32];
+            shared_key.copy_from_slice(shared_sec.as_ref());
 
             let mut rng = rand::thread_rng();
             let nonce: [u8; 12] = rng.gen();
@@ -116,12 +241,105 @@ fn main() -> Result<(), Box<dyn Error>> {
 
             let msg = vote.to_le_bytes();
 
+            let (version, aad) = match (contract, voting_id) {
+                (Some(contract), Some(voting_id)) if !legacy => {
+                    let contract = unhex_ethaddr(&contract)?;
+                    let mgr_addr = pub_key_to_ethaddr(&mgr_key);
+                    let salt = [&contract[..], &mgr_addr, voting_id.as_bytes()].concat();
+                    Hkdf::<Sha256>::new(Some(&salt), shared_sec.as_ref())
+                        .expand(BALLOT_INFO, &mut shared_key)
+                        .map_err(|_| "Key derivation error")?;
+
+                    let aad = [
+                        &[BALLOT_VERSION][..],
+                        &contract,
+                        voting_id.as_bytes(),
+                        &pub_key_to_ethaddr(&PublicKey::from_secret_key(&key)),
+                        &mgr_addr,
+                    ]
+                    .concat();
+                    (hex::encode([BALLOT_VERSION]), aad)
+                }
+                _ => (String::new(), Vec::new()),
+            };
+            let cipher = Aes256Gcm::new(GenericArray::from_slice(&shared_key));
+
             let ct = cipher
-                .encrypt(nonce, msg.as_ref())
+                .encrypt(
+                    nonce,
+                    Payload {
+                        msg: msg.as_ref(),
+                        aad: &aad,
+                    },
+                )
                 .map_err(|e| format!("Encryption error: {}", e).to_string())?;
 
-            println!("CT: {}{}", hex::encode(nonce.as_slice()), hex::encode(&ct));
+            println!(
+                "CT: {}{}{}",
+                version,
+                hex::encode(nonce.as_slice()),
+                hex::encode(&ct)
+            );
+        }
+        Args::VerifyReceipt {
+            contract,
+            voting_id,
+            mgr_addr,
+            sender,
+            ballot_hash,
+            seq,
+            signature,
+        } => {
+            let mut ballot_hash_bytes = [0u8; 32];
+            hex::decode_to_slice(&ballot_hash, &mut ballot_hash_bytes)?;
+            let hash = receipt_hash(
+                &unhex_ethaddr(&contract)?,
+                &voting_id,
+                &unhex_ethaddr(&sender)?,
+                &ballot_hash_bytes,
+                u64::from_str_radix(&seq, 16)?,
+            );
+            if recover_signer(&hash, &signature)? != unhex_ethaddr(&mgr_addr)? {
+                return Err("receipt not signed by the manager".into());
+            }
+            println!("OK");
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_receipt_hash() -> Result<(), Box<dyn Error>> {
+        let contract = [1u8; 20];
+        let sender = [2u8; 20];
+        let ballot_hash = [3u8; 32];
+        let hash = receipt_hash(&contract, "1", &sender, &ballot_hash, 0x105);
+
+        let mut encoded =
+            keccak256(&[b"SgxVotingReceipt(address,bytes,address,bytes32,uint64)"]).to_vec();
+        encoded.extend_from_slice(&[1; 20]);
+        encoded.push(b'1');
+        encoded.extend_from_slice(&[2; 20]);
+        encoded.extend_from_slice(&[3; 32]);
+        encoded.extend_from_slice(&[0; 30]);
+        encoded.extend_from_slice(&[1, 5]);
+        assert_eq!(hash, keccak256(&[&encoded]));
+
+        let mgr_key = SecretKey::parse(&[0x11; 32])?;
+        let (sig, rid) = secp256k1::sign(&secp256k1::Message::parse(&hash), &mgr_key);
+        let signature = format!(
+            "{}{:02x}",
+            hex::encode(sig.serialize().as_ref()),
+            rid.serialize()
+        );
+        let mgr_addr = pub_key_to_ethaddr(&PublicKey::from_secret_key(&mgr_key));
+        assert_eq!(recover_signer(&hash, &signature)?, mgr_addr);
+        let other = receipt_hash(&contract, "1", &sender, &ballot_hash, 0x106);
+        assert_ne!(recover_signer(&other, &signature)?, mgr_addr);
+        Ok(())
+    }
+}
@@ -16,6 +16,20 @@ impl EthHash {
         eth_hash_parts(&[prefix.as_ref(), msg_size.as_ref(), message])
     }
 
+    /// Keccak-256 of the concatenation of `chunks`.
+    pub fn from_parts(chunks: &[impl AsRef<[u8]>]) -> EthHash {
+        eth_hash_parts(chunks)
+    }
+
+    /// Hash signed by `eth_signTypedData_v4`, of a struct hashed as by EIP-712 `hashStruct`.
+    pub fn typed_data(domain: &Eip712Domain, struct_hash: &EthHash) -> EthHash {
+        eth_hash_parts(&[
+            &b"\x19\x01"[..],
+            domain.separator().as_ref(),
+            struct_hash.as_ref(),
+        ])
+    }
+
     pub fn new(signature: &str) -> EthHashBuilder {
         let sig = signature_hash(signature);
         let mut hasher = Keccak::v256();
@@ -45,6 +59,41 @@ impl fmt::LowerHex for EthHash {
     }
 }
 
+/// EIP-712 domain, binding typed data to an application and to a contract on a chain.
+pub struct Eip712Domain<'a> {
+    pub name: &'a str,
+    pub version: &'a str,
+    pub chain_id: u64,
+    pub verifying_contract: &'a EthAddress,
+}
+
+impl Eip712Domain<'_> {
+    pub fn separator(&self) -> EthHash {
+        EthHash::new(
+            "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)",
+        )
+        .add(signature_hash(self.name))
+        .add(signature_hash(self.version))
+        .add(uint_word(self.chain_id))
+        .add(address_word(self.verifying_contract))
+        .build()
+    }
+}
+
+/// ABI encoding of an address, left-padded to 32 bytes.
+pub fn address_word(address: &EthAddress) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[12..].copy_from_slice(&address.0);
+    word
+}
+
+/// ABI encoding of an unsigned integer, big endian in 32 bytes.
+pub fn uint_word(value: u64) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[24..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
 pub fn signature_hash(signature: &str) -> EthHash {
     eth_hash_parts(&[signature.as_bytes()])
 }
@@ -115,6 +164,25 @@ impl EthAddress {
     }
 }
 
+/// Leaf of `address` in a Merkle tree of eligible voters, `keccak256(abi.encodePacked(address))`.
+pub fn merkle_leaf(address: &EthAddress) -> [u8; 32] {
+    eth_hash_parts(&[address]).0
+}
+
+/// Verifies that `leaf` is in the Merkle tree of `root`, as OpenZeppelin `MerkleProof.verify`
+/// does: each pair of nodes is hashed in sorted order.
+pub fn verify_merkle_proof(root: &[u8; 32], leaf: [u8; 32], proof: &[[u8; 32]]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| {
+        let (a, b) = if node <= *sibling {
+            (&node, sibling)
+        } else {
+            (sibling, &node)
+        };
+        eth_hash_parts(&[a, b]).0
+    });
+    computed == *root
+}
+
 fn eth_hash_parts(chunks: &[impl AsRef<[u8]>]) -> EthHash {
     let mut hasher = Keccak::v256();
     for chunk in chunks {
@@ -232,6 +300,89 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_typed_data() -> Result<(), Box<dyn std::error::Error>> {
+        /* Example of the EIP-712 specification. */
+        let contract = EthAddress::from_hex("cccccccccccccccccccccccccccccccccccccccc")?;
+        let domain = Eip712Domain {
+            name: "Ether Mail",
+            version: "1",
+            chain_id: 1,
+            verifying_contract: &contract,
+        };
+        assert_eq!(
+            format!("{:x}", domain.separator()),
+            "f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f"
+        );
+        let person = |name: &str, wallet: &str| -> Result<EthHash, hex::FromHexError> {
+            Ok(EthHash::new("Person(string name,address wallet)")
+                .add(signature_hash(name))
+                .add(address_word(&EthAddress::from_hex(wallet)?))
+                .build())
+        };
+        let mail = EthHash::new(
+            "Mail(Person from,Person to,string contents)Person(string name,address wallet)",
+        )
+        .add(person("Cow", "cd2a3d9f938e13cd947ec05abc7fe734df8dd826")?)
+        .add(person("Bob", "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb")?)
+        .add(signature_hash("Hello, Bob!"))
+        .build();
+        assert_eq!(
+            format!("{:x}", mail),
+            "c52c0ee5d84264471806290a3f2c4cecfc5490626bf912d01f240d7a274b371e"
+        );
+        let hash = EthHash::typed_data(&domain, &mail);
+        assert_eq!(
+            format!("{:x}", hash),
+            "be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2"
+        );
+        let signature = RecoverableSignature::from_hex(concat!(
+            "4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d",
+            "07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b91562",
+            "1c"
+        ))?;
+        assert_eq!(
+            signature.recover_pub_key(&hash)?.to_eth_address(),
+            EthAddress::from_hex("cd2a3d9f938e13cd947ec05abc7fe734df8dd826")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        let node = |a: &[u8; 32], b: &[u8; 32]| eth_hash_parts(&[a.min(b), a.max(b)]).0;
+        let leaves: Vec<_> = (1..=3)
+            .map(|i| merkle_leaf(&EthAddress::new([i; 20])))
+            .collect();
+        assert_eq!(
+            leaves[0].as_ref(),
+            eth_hash_parts(&[hex::decode("01".repeat(20)).unwrap()]).as_ref()
+        );
+        let left = node(&leaves[0], &leaves[1]);
+        let root = node(&left, &leaves[2]);
+
+        assert!(verify_merkle_proof(
+            &root,
+            leaves[0],
+            &[leaves[1], leaves[2]]
+        ));
+        assert!(verify_merkle_proof(
+            &root,
+            leaves[1],
+            &[leaves[0], leaves[2]]
+        ));
+        assert!(verify_merkle_proof(&root, leaves[2], &[left]));
+        assert!(verify_merkle_proof(&root, root, &[]));
+        assert!(!verify_merkle_proof(&root, leaves[2], &[leaves[0]]));
+        assert!(!verify_merkle_proof(
+            &root,
+            leaves[0],
+            &[leaves[2], leaves[1]]
+        ));
+        let outsider = merkle_leaf(&EthAddress::new([4; 20]));
+        assert!(!verify_merkle_proof(&root, outsider, &[left]));
+    }
+
     #[derive(Default)]
     struct FakeDigest(Vec<u8>);
 
@@ -1,8 +1,11 @@
-use crate::voting::Voting;
+use crate::sealing::SealingKey;
+use crate::state::Storage;
+use crate::voting::{Params, Quorum, VotePolicy, Voting};
 
 use crate::eth::EthAddress;
 use anyhow::Context;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
 use structopt::StructOpt;
 
 pub fn prv_path<P: AsRef<Path>>(f: P) -> PathBuf {
@@ -10,8 +13,50 @@ pub fn prv_path<P: AsRef<Path>>(f: P) -> PathBuf {
 }
 
 mod eth;
+mod sealing;
+mod state;
 mod voting;
 
+#[derive(StructOpt)]
+struct Opts {
+    /// file with the enclave sealing key, e.g. /dev/attestation/keys/_sgx_mrenclave.
+    /// Required unless --dev is given.
+    #[structopt(long, env = "VOTING_SEALING_KEY_FILE")]
+    sealing_key_file: Option<PathBuf>,
+    /// seals state with a publicly known development key if no sealing key file is given,
+    /// for testing outside of an enclave only
+    #[structopt(long)]
+    dev: bool,
+    /// reads and migrates state files written before they were sealed
+    #[structopt(long)]
+    allow_unsealed_state: bool,
+    /// accepts ballots of the legacy format, not bound to the election and the sender
+    #[structopt(long)]
+    allow_legacy_ballots: bool,
+    /// current unix time, checked against the deadlines and the last accepted time
+    #[structopt(long)]
+    time: Option<u64>,
+    #[structopt(subcommand)]
+    command: Args,
+}
+
+impl Opts {
+    fn storage(&self) -> anyhow::Result<Storage> {
+        let key = match &self.sealing_key_file {
+            Some(path) => SealingKey::from_file(path)?,
+            None if self.dev => {
+                eprintln!("WARNING: no sealing key given, state is sealed with a development key");
+                SealingKey::dev()
+            }
+            None => anyhow::bail!("no sealing key given, --sealing-key-file or --dev required"),
+        };
+        Ok(Storage {
+            key,
+            allow_unsealed: self.allow_unsealed_state,
+        })
+    }
+}
+
 #[derive(StructOpt)]
 enum Args {
     /// initalizes voting log.
@@ -19,6 +64,32 @@ enum Args {
         /// Example: c73b910e58cb19341ec86111a054547d536d0448
         contract: String,
         voting_id: String,
+        /// whether voters may change their vote: single or last-vote-wins
+        #[structopt(long, default_value = "single")]
+        vote_policy: VotePolicy,
+        /// votes required to report the results, as a number or a percentage of voters
+        #[structopt(long, default_value = "100%")]
+        quorum: Quorum,
+        /// unix time after which registrations are rejected
+        #[structopt(long)]
+        registration_deadline: Option<u64>,
+        /// unix time after which votes are rejected
+        #[structopt(long)]
+        voting_deadline: Option<u64>,
+        /// address allowed to register, may be given multiple times.
+        /// Anyone may register if no eligible voters are given.
+        #[structopt(long = "eligible", number_of_values = 1)]
+        eligible: Vec<String>,
+        /// file in /private listing addresses allowed to register, separated by whitespace
+        #[structopt(long)]
+        eligible_file: Option<PathBuf>,
+        /// Merkle root of addresses allowed to register, with leaves keccak256(address) and
+        /// pairs hashed in sorted order. Voters register with a proof.
+        #[structopt(long, conflicts_with_all = &["eligible", "eligible-file"])]
+        eligible_root: Option<String>,
+        /// chain of the contract; voters may then sign registrations as EIP-712 typed data
+        #[structopt(long)]
+        chain_id: Option<u64>,
     },
     /// registers a voter
     Register {
@@ -29,6 +100,10 @@ enum Args {
         sender: String,
         signature: String,
         session_pub_key: String,
+        /// node of the Merkle proof of the sender being eligible, from the leaf up; may be given
+        /// multiple times
+        #[structopt(long = "proof", number_of_values = 1)]
+        proof: Vec<String>,
     },
     /// starts the voting
     Start {
@@ -36,7 +111,8 @@ enum Args {
         voting_id: String,
         operator_addr: String,
     },
-    /// adds an encrypted vote
+    /// adds an encrypted vote, prints the encrypted response and the receipt: the sequence
+    /// number, the hash of the ballot and the signature
     Vote {
         contract: String,
         voting_id: String,
@@ -44,12 +120,24 @@ enum Args {
         sender: String,
         encrypted_vote: String,
     },
-    /// prints voting summary
+    /// closes the voting, no votes are accepted afterwards
+    Close {
+        contract: String,
+        voting_id: String,
+        operator_addr: String,
+    },
+    /// prints signed voting summary: the number of abstentions, the hash of eligible voters and
+    /// results. Voting must be closed or past its deadline.
     Report {
         contract: String,
         voting_id: String,
         operator_addr: String,
     },
+    /// lists stored votings, one per line after the count
+    List {
+        /// lists only votings of this contract
+        contract: Option<String>,
+    },
 }
 
 fn main() {
@@ -59,15 +147,93 @@ fn main() {
     }
 }
 
+fn eligible_voters(
+    mut addresses: Vec<String>,
+    file: Option<PathBuf>,
+) -> anyhow::Result<Option<Vec<EthAddress>>> {
+    if let Some(file) = &file {
+        let mut components = file.components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        ) {
+            anyhow::bail!("eligible voters file must be a file name in /private");
+        }
+        let path = prv_path(file);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        addresses.extend(content.split_whitespace().map(String::from));
+    }
+    if addresses.is_empty() && file.is_none() {
+        return Ok(None);
+    }
+    addresses
+        .iter()
+        .map(|address| {
+            EthAddress::from_hex(address.trim_start_matches("0x"))
+                .with_context(|| format!("invalid eligible voter {}", address))
+        })
+        .collect::<anyhow::Result<_>>()
+        .map(Some)
+}
+
+fn parse_hash(hash: &str) -> anyhow::Result<[u8; 32]> {
+    let mut bytes = [0; 32];
+    hex::decode_to_slice(hash.trim_start_matches("0x"), &mut bytes)
+        .with_context(|| format!("invalid hash {}", hash))?;
+    Ok(bytes)
+}
+
+fn load(
+    contract: &str,
+    voting_id: &str,
+    operator_addr: &str,
+    storage: &Storage,
+    time: Option<u64>,
+) -> anyhow::Result<Voting> {
+    let mut v = Voting::load(contract, voting_id, operator_addr, storage)?;
+    if let Some(time) = time {
+        v.set_time(time).context("time")?;
+    }
+    Ok(v)
+}
+
 fn run() -> anyhow::Result<()> {
-    match Args::from_args() {
+    let opts = Opts::from_args();
+    let storage = opts.storage()?;
+    match opts.command {
         Args::Init {
             contract,
             voting_id,
+            vote_policy,
+            quorum,
+            registration_deadline,
+            voting_deadline,
+            eligible,
+            eligible_file,
+            eligible_root,
+            chain_id,
         } => {
+            if let (Some(registration), Some(voting)) = (registration_deadline, voting_deadline) {
+                if voting < registration {
+                    anyhow::bail!("voting deadline before the registration deadline");
+                }
+            }
             let contract_addr = EthAddress::from_hex(contract.as_str())?;
-            let v = Voting::new(contract_addr, voting_id);
-            v.save().context("init save")?;
+            let params = Params {
+                policy: vote_policy,
+                quorum,
+                registration_deadline,
+                voting_deadline,
+                eligible: eligible_voters(eligible, eligible_file)?,
+                eligible_root: eligible_root.as_deref().map(parse_hash).transpose()?,
+                chain_id,
+            };
+            let mut v = Voting::new(contract_addr, voting_id, params);
+            if let Some(time) = opts.time {
+                v.set_time(time)?;
+            }
+            v.save(&storage).context("init save")?;
             let op_addr = hex::encode(&v.operator_address());
             let op_pkey = hex::encode(v.operator_pubkey().as_ref());
             println!("OK {} {}", op_addr, op_pkey);
@@ -77,9 +243,10 @@ fn run() -> anyhow::Result<()> {
             voting_id,
             operator_addr,
         } => {
-            let mut v = Voting::load(&contract, &voting_id, &operator_addr).context("load")?;
+            let mut v =
+                load(&contract, &voting_id, &operator_addr, &storage, opts.time).context("load")?;
             let list = v.start().context("start")?;
-            v.save().context("save")?;
+            v.save(&storage).context("save")?;
             println!("OK {}", list);
         }
         Args::Register {
@@ -89,13 +256,19 @@ fn run() -> anyhow::Result<()> {
             sender,
             signature,
             session_pub_key,
+            proof,
         } => {
-            let mut v = Voting::load(&contract, &voting_id, &operator_addr)
+            let mut v = load(&contract, &voting_id, &operator_addr, &storage, opts.time)
                 .with_context(|| "loading state")?;
+            let proof = proof
+                .iter()
+                .map(|node| parse_hash(node))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .context("proof")?;
             let ticket = v
-                .register(&sender, &signature, &session_pub_key)
+                .register(&sender, &signature, &session_pub_key, &proof)
                 .context("register")?;
-            v.save().context("save")?;
+            v.save(&storage).context("save")?;
             println!("OK {}", ticket);
         }
         Args::Vote {
@@ -105,25 +278,70 @@ fn run() -> anyhow::Result<()> {
             sender,
             encrypted_vote,
         } => {
-            let mut v = Voting::load(&contract, &voting_id, &operator_addr)?;
-            let response = v.vote(&sender, &encrypted_vote)?;
-            v.save()?;
-            println!("OK {}", response);
+            let mut v = load(&contract, &voting_id, &operator_addr, &storage, opts.time)?;
+            let receipt = v.vote(&sender, &encrypted_vote, opts.allow_legacy_ballots)?;
+            v.save(&storage)?;
+            println!(
+                "OK {} {:x} {} {}",
+                hex::encode(receipt.response),
+                receipt.seq,
+                hex::encode(receipt.ballot_hash),
+                receipt.signature
+            );
+        }
+        Args::Close {
+            contract,
+            voting_id,
+            operator_addr,
+        } => {
+            let mut v = load(&contract, &voting_id, &operator_addr, &storage, opts.time)?;
+            v.close()?;
+            v.save(&storage)?;
+            println!("OK");
         }
         Args::Report {
             contract,
             voting_id,
             operator_addr,
         } => {
-            let v = Voting::load(&contract, &voting_id, &operator_addr)?;
-            let (results, signature) = v.report()?;
+            let mut v = load(&contract, &voting_id, &operator_addr, &storage, opts.time)?;
+            let report = v.report()?;
+            v.save(&storage)?;
 
-            let formated_results = results
+            let formated_results = report
+                .results
                 .into_iter()
                 .map(|(k, v)| format!("{:x} {:x}", k, v))
                 .collect::<Vec<_>>()
                 .join(" ");
-            println!("OK {} {}", signature, formated_results);
+            println!(
+                "OK {} {:x} {} {}",
+                report.signature,
+                report.abstentions,
+                hex::encode(report.eligible_hash),
+                formated_results
+            );
+        }
+        Args::List { contract } => {
+            let votings = Voting::list(contract.as_deref(), &storage)?;
+            println!("OK {}", votings.len());
+            for v in votings {
+                println!(
+                    "{:x} {} {:x} {} {}/{}",
+                    v.contract,
+                    v.voting_id,
+                    v.operator,
+                    if v.closed {
+                        "closed"
+                    } else if v.started {
+                        "started"
+                    } else {
+                        "registering"
+                    },
+                    v.votes,
+                    v.voters
+                );
+            }
         }
     }
     Ok(())
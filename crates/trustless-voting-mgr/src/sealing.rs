@@ -0,0 +1,109 @@
+//! Sealing of the voting state at rest.
+//!
+//! State files are encrypted and authenticated with AES-256-GCM, under a key derived from a
+//! sealing key provided by the enclave, e.g. `/dev/attestation/keys/_sgx_mrenclave` in Graphene.
+use crate::voting::VotingError;
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes256Gcm,
+};
+use anyhow::Context;
+use rand_core::RngCore;
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+use wasi_rng::WasiRng;
+
+const NONCE_LEN: usize = 12;
+
+pub struct SealingKey([u8; 32]);
+
+impl SealingKey {
+    /// Derives the key from the content of `path`, which must be at least 16 bytes long.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = fs::read(path)
+            .with_context(|| format!("failed to read sealing key {}", path.display()))?;
+        if content.len() < 16 {
+            anyhow::bail!("sealing key {} is too short", path.display());
+        }
+        Ok(Self::derive(&content))
+    }
+
+    /// Publicly known key, for development outside of an enclave only.
+    pub fn dev() -> Self {
+        Self::derive(b"trustless-voting-mgr development key")
+    }
+
+    fn derive(material: &[u8]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.input(b"SgxVotingState");
+        hasher.input(material);
+        let mut key = [0u8; 32];
+        key.copy_from_slice(hasher.result().as_slice());
+        SealingKey(key)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(GenericArray::from_slice(&self.0))
+    }
+
+    /// Encrypts `state`, authenticating it along with `header`. Returns the nonce followed by
+    /// the ciphertext.
+    pub fn seal(&self, header: &[u8], state: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_LEN];
+        WasiRng.fill_bytes(&mut nonce);
+        let sealed = self
+            .cipher()
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: state,
+                    aad: header,
+                },
+            )
+            .map_err(|e| anyhow::anyhow!("EncryptionError: {}", e))?;
+        let mut data = Vec::with_capacity(NONCE_LEN + sealed.len());
+        data.extend_from_slice(&nonce);
+        data.extend(sealed);
+        Ok(data)
+    }
+
+    pub fn unseal(&self, header: &[u8], data: &[u8]) -> Result<Vec<u8>, VotingError> {
+        if data.len() < NONCE_LEN {
+            return Err(VotingError::InvalidState);
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: header,
+                },
+            )
+            .map_err(|_| VotingError::InvalidState)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_seal() -> anyhow::Result<()> {
+        let key = SealingKey::derive(&[1; 16]);
+        let sealed = key.seal(b"header", b"secret state")?;
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert_eq!(key.unseal(b"header", &sealed)?, b"secret state");
+
+        assert!(key.unseal(b"other", &sealed).is_err());
+        assert!(SealingKey::dev().unseal(b"header", &sealed).is_err());
+        for i in 0..sealed.len() {
+            let mut tampered = sealed.clone();
+            tampered[i] ^= 1;
+            assert!(key.unseal(b"header", &tampered).is_err());
+        }
+        assert!(key.unseal(b"header", &sealed[..sealed.len() - 1]).is_err());
+        assert!(key.unseal(b"header", b"plain").is_err());
+        Ok(())
+    }
+}
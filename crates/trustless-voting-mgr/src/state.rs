@@ -0,0 +1,349 @@
+//! Versioned file format of the voting state.
+//!
+//! | version | layout                                       |
+//! |---------|----------------------------------------------|
+//! | 0       | bincode `v0::BinaryState`, not sealed        |
+//! | 1       | `SGXVST01`, sealed bincode `BinaryState`     |
+//!
+//! Version 0 is migrated when read, and only if explicitly allowed, as nothing protects it from
+//! being modified on the host.
+//!
+//! Changes since the snapshot are appended to a journal, as records of a 32-bit little endian
+//! length followed by a sealed bincode `Record`. Records are authenticated along with the
+//! election they belong to.
+use crate::{
+    sealing::SealingKey,
+    voting::{Quorum, VotePolicy, VotingError},
+};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+pub const VERSION: u16 = 1;
+
+/// How state files are read and written.
+pub struct Storage {
+    pub key: SealingKey,
+    /// Read state files of version 0.
+    pub allow_unsealed: bool,
+}
+
+const MAGIC: &[u8; 6] = b"SGXVST";
+const HEADER_LEN: usize = 8;
+const JOURNAL_MAGIC: &[u8; 8] = b"SGXVJR01";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BinaryState {
+    pub secret: Vec<u8>,
+    pub contract: [u8; 20],
+    pub voting_id: String,
+    pub started: bool,
+    /// Address, session public key and whether voted, sorted by address.
+    pub voters: Vec<([u8; 20], Vec<u8>, bool)>,
+    /// Sorted by option.
+    pub results: Vec<(u32, u32)>,
+    /// Sequence number of the last journal record applied.
+    pub seq: u64,
+    pub policy: VotePolicy,
+    /// Last choice of each voter, kept only if re-voting is allowed. Sorted by address.
+    pub choices: Vec<([u8; 20], u32)>,
+    /// Voter and hash of each accepted ballot, kept only if re-voting is allowed. Sorted.
+    pub ballots: Vec<([u8; 20], [u8; 32])>,
+    pub quorum: Quorum,
+    pub closed: bool,
+    /// Unix time after which registrations and votes are rejected.
+    pub registration_deadline: Option<u64>,
+    pub voting_deadline: Option<u64>,
+    /// Last accepted unix time.
+    pub time: u64,
+    /// Addresses allowed to register, sorted, or `None` if anyone may register.
+    pub eligible: Option<Vec<[u8; 20]>>,
+    /// Merkle root of the addresses allowed to register, proven by each registering voter.
+    pub eligible_root: Option<[u8; 32]>,
+    /// Chain of the EIP-712 domain of registrations.
+    pub chain_id: Option<u64>,
+}
+
+/// Change of the state, recorded in the journal.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Operation {
+    Register {
+        voter: [u8; 20],
+        session_key: Vec<u8>,
+    },
+    Start,
+    Vote {
+        voter: [u8; 20],
+        option: u32,
+        /// Keccak-256 of the ballot as sent.
+        ballot: [u8; 32],
+    },
+    /// Results were reported, which closes voting.
+    Report,
+    Close,
+    /// Time passed in with an operation, which later operations may not precede.
+    Time {
+        now: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    pub seq: u64,
+    pub op: Operation,
+}
+
+mod v0 {
+    use crate::voting::{Quorum, VotePolicy};
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize)]
+    pub struct BinaryState {
+        pub secret: Vec<u8>,
+        pub contract: [u8; 20],
+        pub voting_id: String,
+        pub started: bool,
+        pub voters: HashMap<[u8; 20], (Vec<u8>, bool)>,
+        pub results: HashMap<u32, u32>,
+    }
+
+    impl From<BinaryState> for super::BinaryState {
+        fn from(state: BinaryState) -> Self {
+            let mut voters: Vec<_> = state
+                .voters
+                .into_iter()
+                .map(|(address, (session_key, voted))| (address, session_key, voted))
+                .collect();
+            voters.sort();
+            let mut results: Vec<_> = state.results.into_iter().collect();
+            results.sort();
+            super::BinaryState {
+                secret: state.secret,
+                contract: state.contract,
+                voting_id: state.voting_id,
+                started: state.started,
+                voters,
+                results,
+                seq: 0,
+                policy: VotePolicy::Single,
+                choices: Vec::new(),
+                ballots: Vec::new(),
+                /* Every voter had to vote. */
+                quorum: Quorum::Percent(100),
+                closed: false,
+                registration_deadline: None,
+                voting_deadline: None,
+                time: 0,
+                eligible: None,
+                eligible_root: None,
+                chain_id: None,
+            }
+        }
+    }
+}
+
+fn header(version: u16) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(format!("{:02}", version).as_bytes());
+    header
+}
+
+pub fn encode(state: &BinaryState, key: &SealingKey) -> anyhow::Result<Vec<u8>> {
+    let header = header(VERSION);
+    let sealed = key.seal(&header, &bincode::serialize(state)?)?;
+    Ok([header, sealed].concat())
+}
+
+/// Decodes state of any supported version. Returns the state migrated to the current format,
+/// and the version it was stored in.
+pub fn decode(
+    data: &[u8],
+    key: &SealingKey,
+    allow_unsealed: bool,
+) -> anyhow::Result<(BinaryState, u16)> {
+    if !data.starts_with(MAGIC) {
+        if !allow_unsealed {
+            return Err(VotingError::UnsealedState.into());
+        }
+        let state: v0::BinaryState = bincode::deserialize(data).context("invalid state")?;
+        return Ok((state.into(), 0));
+    }
+    if data.len() < HEADER_LEN {
+        return Err(VotingError::InvalidState.into());
+    }
+    let (header, sealed) = data.split_at(HEADER_LEN);
+    let version = std::str::from_utf8(&header[MAGIC.len()..])
+        .ok()
+        .and_then(|version| version.parse::<u16>().ok())
+        .ok_or(VotingError::InvalidState)?;
+    if version != VERSION {
+        return Err(VotingError::UnsupportedVersion(version).into());
+    }
+    let state = bincode::deserialize(&key.unseal(header, sealed)?).context("invalid state")?;
+    Ok((state, version))
+}
+
+fn journal_aad(scope: &[u8]) -> Vec<u8> {
+    [JOURNAL_MAGIC.as_ref(), scope].concat()
+}
+
+/// Encodes a journal record of the election identified by `scope`.
+pub fn encode_record(record: &Record, scope: &[u8], key: &SealingKey) -> anyhow::Result<Vec<u8>> {
+    let sealed = key.seal(&journal_aad(scope), &bincode::serialize(record)?)?;
+    let mut data = (sealed.len() as u32).to_le_bytes().to_vec();
+    data.extend(sealed);
+    Ok(data)
+}
+
+/// Decodes journal records. An incomplete last record, left by an interrupted append, is
+/// ignored; its length is returned along with the records.
+pub fn decode_records(
+    mut data: &[u8],
+    scope: &[u8],
+    key: &SealingKey,
+) -> anyhow::Result<(Vec<Record>, usize)> {
+    let aad = journal_aad(scope);
+    let mut records = Vec::new();
+    while data.len() >= 4 {
+        let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() - 4 < len {
+            break;
+        }
+        let record = key.unseal(&aad, &data[4..4 + len])?;
+        records.push(bincode::deserialize(&record).context("invalid journal record")?);
+        data = &data[4 + len..];
+    }
+    Ok((records, data.len()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /* Written by the respective versions, with `SealingKey::dev`. */
+    const V0: &str = concat!(
+        "20000000000000000101010101010101010101010101010101010101010101010101010101010101",
+        "02020202020202020202020202020202020202020100000000000000310102000000000000000303",
+        "03030303030303030303030303030303030341000000000000000404040404040404040404040404",
+        "04040404040404040404040404040404040404040404040404040404040404040404040404040404",
+        "04040404040404040404040105050505050505050505050505050505050505054100000000000000",
+        "06060606060606060606060606060606060606060606060606060606060606060606060606060606",
+        "06060606060606060606060606060606060606060606060606000200000000000000000000000200",
+        "00000100000001000000",
+    );
+    const V1: &str = concat!(
+        "5347585653543031ba0d12c5b295f05e653008360bc862bca2fdfe1b7dc4ea21b4099554d06cba13",
+        "9ef7f955b942432ca744eccfed2678d1a3ad8766fe7f95f4c8e79d2a8bf6a55cdadd422cd0fcf502",
+        "94bf0a9da0d2fd4c738bf1af7cbb6a2d0aeef4d9af38bcb13fac21733e7ea8280191b0b7a89669b3",
+        "62ea972c88fd9af05e4b65ea01e8330c0558e471a7560dbbac8ad1cb7ed5ae6669e9a4083881f79f",
+        "ce54c54f1d7a592741ec5975dc1d10798bd5d151f75bb70a2ddf3265215cdeff325c1a3f52661769",
+        "1f37ea2e63afc4e74ce58e00c49b223212ce318cdd46c6026ea36669eef364b2f248cc83de06d964",
+        "a0c3c275e1b252d9c094c050408b4db1f5b394fd9c3010d6f4d74a7cc92ed930ba94cae2cff4b0a3",
+        "2d0d13662650af45456a6384a9937b84a3c8c0c1146535c3d6e86e00896e098682209bc6ec3526f6",
+        "7140819d2eab3c8949fe0be21165872ce85de4c611af079097947c4eaec739342d0880167ef7b553",
+        "7a185e0d0d38a1cc736e52b138b78f92598f2d1429179507afe0c33282feba64caaae829b49ab0c0",
+        "c79368d23ffa747bd7f92dcb651ccb7862e36934be2a18b10e69bc06ccb100cd0cbbdb9022c68b9a",
+        "35391914ea3eae084f3dc2f23a9a6b71167b7be78dab7c7952769103b948d76bd7ce6359782b1cc8",
+        "1de2b5e91b6d6d21efade2b55cd0c234a6617acbf4e10d4247f9bb9a2fdbb99088c65b46e0e2ba56",
+        "690b818d90cefa0884e59f3e4b369956866539fd695afd4bc605c84160990ee945fe93ef4be7f962",
+        "abf25b11c8ec939dbbdca58ee8c16d6fc44d57743378838142754b27c0aac59e699629e6fbd7ec1d",
+        "af7484b868c8c804",
+    );
+
+    fn expected() -> BinaryState {
+        BinaryState {
+            secret: vec![1; 32],
+            contract: [2; 20],
+            voting_id: "1".into(),
+            started: true,
+            voters: vec![([3; 20], vec![4; 65], true), ([5; 20], vec![6; 65], false)],
+            results: vec![(0, 2), (1, 1)],
+            seq: 0,
+            policy: VotePolicy::Single,
+            choices: Vec::new(),
+            ballots: Vec::new(),
+            quorum: Quorum::Percent(100),
+            closed: false,
+            registration_deadline: None,
+            voting_deadline: None,
+            time: 0,
+            eligible: None,
+            eligible_root: None,
+            chain_id: None,
+        }
+    }
+
+    fn current() -> BinaryState {
+        BinaryState {
+            seq: 5,
+            policy: VotePolicy::LastVoteWins,
+            choices: vec![([3; 20], 1)],
+            ballots: vec![([3; 20], [7; 32]), ([3; 20], [8; 32])],
+            quorum: Quorum::Votes(1),
+            closed: true,
+            registration_deadline: Some(1_600_000_000),
+            voting_deadline: Some(1_600_001_000),
+            time: 1_600_000_500,
+            eligible: Some(vec![[3; 20], [5; 20]]),
+            eligible_root: Some([6; 32]),
+            chain_id: Some(4),
+            ..expected()
+        }
+    }
+
+    #[test]
+    fn test_fixtures() -> anyhow::Result<()> {
+        let key = SealingKey::dev();
+        let v0 = hex::decode(V0)?;
+        assert!(decode(&v0, &key, false).is_err());
+        assert_eq!(decode(&v0, &key, true)?, (expected(), 0));
+        assert_eq!(decode(&hex::decode(V1)?, &key, false)?, (current(), 1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_current() -> anyhow::Result<()> {
+        let key = SealingKey::dev();
+        let data = encode(&current(), &key)?;
+        assert!(data.starts_with(b"SGXVST01"));
+        assert_eq!(decode(&data, &key, false)?, (current(), VERSION));
+
+        let mut future = data;
+        future[7] = b'2';
+        assert!(decode(&future, &key, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_journal() -> anyhow::Result<()> {
+        let key = SealingKey::dev();
+        let records = vec![
+            Record {
+                seq: 1,
+                op: Operation::Register {
+                    voter: [1; 20],
+                    session_key: vec![2; 65],
+                },
+            },
+            Record {
+                seq: 2,
+                op: Operation::Start,
+            },
+        ];
+        let mut data = Vec::new();
+        for record in &records {
+            data.extend(encode_record(record, b"scope", &key)?);
+        }
+        assert_eq!(decode_records(&data, b"scope", &key)?, (records.clone(), 0));
+        assert!(decode_records(&data, b"other", &key).is_err());
+
+        let (decoded, partial) = decode_records(&data[..data.len() - 3], b"scope", &key)?;
+        assert_eq!(decoded, records[..1].to_vec());
+        assert!(partial > 0);
+
+        let mut tampered = data;
+        tampered[10] ^= 1;
+        assert!(decode_records(&tampered, b"scope", &key).is_err());
+        Ok(())
+    }
+}
@@ -1,19 +1,22 @@
-use crate::eth::{EthAddress, EthHash, RecoverableSignature, ToEthAddress};
+use crate::eth::{self, EthAddress, EthHash, RecoverableSignature, ToEthAddress};
+use crate::state::{self, BinaryState, Operation, Record, Storage};
 use aes_gcm::{
-    aead::{generic_array::GenericArray, Aead, NewAead},
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
     Aes256Gcm,
 };
 use anyhow::Context;
+use hkdf::Hkdf;
 use rand_core::RngCore;
 use secp256k1::{PublicKey, SecretKey};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{
-    collections::{hash_map, HashMap},
+    collections::{hash_map, HashMap, HashSet},
     convert::TryInto,
     fs,
-    io::BufReader,
+    io::Write,
+    str::FromStr,
 };
 use thiserror::Error;
 use wasi_rng::WasiRng;
@@ -24,19 +27,135 @@ pub enum VotingError {
     AlreadyStarted,
     #[error("already voted")]
     AlreadyVoted,
+    #[error("ballot already accepted")]
+    ReplayedBallot,
     #[error("DecryptionError")]
     DecryptionError,
     #[error("InvalidAddress")]
     InvalidAddress,
-    #[allow(unused)]
     #[error("InvalidId")]
     InvalidId,
     #[error("NotFinished")]
     NotFinished,
     #[error("NotStarted")]
     NotStarted,
+    #[error("voting closed")]
+    Closed,
+    #[error("deadline passed")]
+    DeadlinePassed,
+    #[error("NotEligible: sender is not an eligible voter")]
+    NotEligible,
+    #[error("InvalidTime: time is missing or precedes the last accepted time")]
+    InvalidTime,
+    #[error("InvalidState: state file was modified or sealed with another key")]
+    InvalidState,
+    #[error("UnsealedState: state file is not sealed")]
+    UnsealedState,
+    #[error("UnsupportedVersion: state format version {0}")]
+    UnsupportedVersion(u16),
 }
 
+/// Whether voters may change their vote.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum VotePolicy {
+    /// A second ballot is rejected.
+    Single,
+    /// A ballot replaces the previous one of the voter.
+    LastVoteWins,
+}
+
+impl FromStr for VotePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(VotePolicy::Single),
+            "last-vote-wins" => Ok(VotePolicy::LastVoteWins),
+            _ => anyhow::bail!("unknown vote policy: {}", s),
+        }
+    }
+}
+
+/// Votes required to report the results.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Quorum {
+    Votes(u32),
+    /// Percentage of registered voters, rounded up.
+    Percent(u8),
+}
+
+impl Quorum {
+    fn required(self, voters: usize) -> usize {
+        match self {
+            Quorum::Votes(votes) => votes as usize,
+            Quorum::Percent(percent) => (voters * percent as usize + 99) / 100,
+        }
+    }
+}
+
+impl FromStr for Quorum {
+    type Err = anyhow::Error;
+
+    /// Parses a number of votes, or a percentage of voters followed by `%`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse()? {
+                percent @ 0..=100 => Ok(Quorum::Percent(percent)),
+                _ => anyhow::bail!("quorum above 100%: {}", s),
+            },
+            None => Ok(Quorum::Votes(s.parse()?)),
+        }
+    }
+}
+
+/// Parameters of an election, fixed at `init`.
+pub struct Params {
+    pub policy: VotePolicy,
+    pub quorum: Quorum,
+    /// Unix time after which registrations are rejected.
+    pub registration_deadline: Option<u64>,
+    /// Unix time after which votes are rejected.
+    pub voting_deadline: Option<u64>,
+    /// Addresses allowed to register, anyone may register if `None`.
+    pub eligible: Option<Vec<EthAddress>>,
+    /// Merkle root of the addresses allowed to register (see `eth::verify_merkle_proof`).
+    pub eligible_root: Option<[u8; 32]>,
+    /// Chain of the contract, registrations may be signed as EIP-712 typed data if given.
+    pub chain_id: Option<u64>,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            policy: VotePolicy::Single,
+            quorum: Quorum::Percent(100),
+            registration_deadline: None,
+            voting_deadline: None,
+            eligible: None,
+            eligible_root: None,
+            chain_id: None,
+        }
+    }
+}
+
+/// Version of the ballot format: the version byte, the nonce and the ciphertext of the vote,
+/// authenticated along with the election and the sender (see `Voting::ballot_aad`). Ballots and
+/// receipts are encrypted with separate keys derived by HKDF-SHA256 from the ECDH shared secret.
+/// Legacy ballots, without the version byte, are encrypted with the shared secret itself and
+/// have nothing authenticated along.
+const BALLOT_VERSION: u8 = 3;
+
+/// HKDF info of the ballot and receipt keys.
+const BALLOT_INFO: &[u8] = b"SgxVoting ballot";
+const RECEIPT_INFO: &[u8] = b"SgxVoting receipt";
+
+/// EIP-712 domain name and version of registrations.
+const DOMAIN_NAME: &str = "SgxVoting";
+const DOMAIN_VERSION: &str = "1";
+
+/// Number of journal records after which the state is compacted into a new snapshot.
+const COMPACTION_THRESHOLD: usize = 64;
+
 pub struct Voting {
     secret: SecretKey,
     contract: EthAddress,
@@ -44,16 +163,65 @@ pub struct Voting {
     started: bool,
     voters: HashMap<EthAddress, (PublicKey, bool)>,
     results: HashMap<u32, u32>,
+    policy: VotePolicy,
+    /// Last choice of each voter, kept only under `VotePolicy::LastVoteWins`.
+    choices: HashMap<EthAddress, u32>,
+    /// Voter and hash of each accepted ballot, kept only under `VotePolicy::LastVoteWins`, so
+    /// that an earlier ballot cannot be sent again to replace the latest one.
+    ballots: HashSet<([u8; 20], [u8; 32])>,
+    quorum: Quorum,
+    /// Set when closed or once the results are reported, no votes are accepted afterwards.
+    closed: bool,
+    registration_deadline: Option<u64>,
+    voting_deadline: Option<u64>,
+    /// Last accepted unix time.
+    time: u64,
+    /// Time passed in with the current operation, if any.
+    now: Option<u64>,
+    eligible: Option<HashSet<EthAddress>>,
+    eligible_root: Option<[u8; 32]>,
+    chain_id: Option<u64>,
+    /// Sequence number of the last operation applied.
+    seq: u64,
+    /// Operations applied since the last save.
+    pending: Vec<Record>,
+    /// Number of records in the journal, `None` until a snapshot is saved.
+    journaled: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct BinaryState {
-    secret: Vec<u8>,
-    contract: [u8; 20],
-    voting_id: String,
-    started: bool,
-    voters: HashMap<[u8; 20], (Vec<u8>, bool)>,
-    results: HashMap<u32, u32>,
+/// Signed results of an election.
+#[derive(Debug)]
+pub struct Report {
+    /// Votes for each option, sorted by option.
+    pub results: Vec<(u32, u32)>,
+    pub abstentions: u32,
+    /// See `Voting::eligible_hash`.
+    pub eligible_hash: [u8; 32],
+    pub signature: String,
+}
+
+/// Accepted ballot, signed so that the voter may prove it was accepted.
+#[derive(Debug)]
+pub struct Receipt {
+    /// `ACCEPTED` encrypted to the voter session, the nonce followed by the ciphertext.
+    pub response: Vec<u8>,
+    /// Keccak-256 of the ballot as sent.
+    pub ballot_hash: [u8; 32],
+    /// Sequence number of the journal record of the vote.
+    pub seq: u64,
+    /// See `Voting::receipt_hash`.
+    pub signature: String,
+}
+
+/// Stored election, as listed by `Voting::list`.
+pub struct Summary {
+    pub contract: EthAddress,
+    pub voting_id: String,
+    pub operator: EthAddress,
+    pub started: bool,
+    pub closed: bool,
+    pub voters: usize,
+    pub votes: usize,
 }
 
 impl BinaryState {
@@ -62,12 +230,18 @@ impl BinaryState {
         let contract = v.contract.to_array();
         let voting_id = v.voting_id.clone();
         let started = v.started;
-        let voters = v
+        let mut voters: Vec<_> = v
             .voters
             .iter()
-            .map(|(k, (p, v))| (k.to_array(), (Vec::from(p.serialize().as_ref()), *v)))
+            .map(|(k, (p, v))| (k.to_array(), Vec::from(p.serialize().as_ref()), *v))
             .collect();
-        let results = v.results.clone();
+        voters.sort();
+        let mut results: Vec<_> = v.results.iter().map(|(k, v)| (*k, *v)).collect();
+        results.sort();
+        let mut choices: Vec<_> = v.choices.iter().map(|(k, v)| (k.to_array(), *v)).collect();
+        choices.sort();
+        let mut ballots: Vec<_> = v.ballots.iter().copied().collect();
+        ballots.sort();
         Self {
             secret,
             contract,
@@ -75,6 +249,18 @@ impl BinaryState {
             started,
             voters,
             results,
+            seq: v.seq,
+            policy: v.policy,
+            choices,
+            ballots,
+            quorum: v.quorum,
+            closed: v.closed,
+            registration_deadline: v.registration_deadline,
+            voting_deadline: v.voting_deadline,
+            time: v.time,
+            eligible: v.eligible.as_ref().map(sorted_addresses),
+            eligible_root: v.eligible_root,
+            chain_id: v.chain_id,
         }
     }
 
@@ -86,9 +272,14 @@ impl BinaryState {
         let voters = self
             .voters
             .into_iter()
-            .map(|(k, (p, v))| Ok((EthAddress::new(k), (PublicKey::parse_slice(&p, None)?, v))))
+            .map(|(k, p, v)| Ok((EthAddress::new(k), (PublicKey::parse_slice(&p, None)?, v))))
             .collect::<anyhow::Result<_>>()?;
-        let results = self.results;
+        let results = self.results.into_iter().collect();
+        let choices = self
+            .choices
+            .into_iter()
+            .map(|(k, v)| (EthAddress::new(k), v))
+            .collect();
         Ok(Voting {
             secret,
             contract,
@@ -96,12 +287,29 @@ impl BinaryState {
             started,
             voters,
             results,
+            policy: self.policy,
+            choices,
+            ballots: self.ballots.into_iter().collect(),
+            quorum: self.quorum,
+            closed: self.closed,
+            registration_deadline: self.registration_deadline,
+            voting_deadline: self.voting_deadline,
+            time: self.time,
+            now: None,
+            eligible: self
+                .eligible
+                .map(|eligible| eligible.into_iter().map(EthAddress::new).collect()),
+            eligible_root: self.eligible_root,
+            chain_id: self.chain_id,
+            seq: self.seq,
+            pending: Vec::new(),
+            journaled: Some(0),
         })
     }
 }
 
 impl Voting {
-    pub fn new(contract: EthAddress, voting_id: String) -> Self {
+    pub fn new(contract: EthAddress, voting_id: String, params: Params) -> Self {
         let mut os_rng = wasi_rng::WasiRng::default();
         let secret = SecretKey::random(&mut os_rng);
         Self {
@@ -111,6 +319,23 @@ impl Voting {
             started: false,
             voters: HashMap::new(),
             results: HashMap::new(),
+            policy: params.policy,
+            choices: HashMap::new(),
+            ballots: HashSet::new(),
+            quorum: params.quorum,
+            closed: false,
+            registration_deadline: params.registration_deadline,
+            voting_deadline: params.voting_deadline,
+            time: 0,
+            now: None,
+            eligible: params
+                .eligible
+                .map(|eligible| eligible.into_iter().collect()),
+            eligible_root: params.eligible_root,
+            chain_id: params.chain_id,
+            seq: 0,
+            pending: Vec::new(),
+            journaled: None,
         }
     }
 
@@ -122,48 +347,399 @@ impl Voting {
         PublicKey::from_secret_key(&self.secret).serialize()
     }
 
-    pub fn save(&self) -> anyhow::Result<()> {
-        let file_name = Self::storage_path(&self.voting_id);
+    /// Appends operations applied since the last save to the journal, or compacts the journal
+    /// into a new snapshot once it reaches `COMPACTION_THRESHOLD` records.
+    pub fn save(&mut self, storage: &Storage) -> anyhow::Result<()> {
+        match self.journaled {
+            Some(journaled) if journaled + self.pending.len() < COMPACTION_THRESHOLD => {
+                self.append(storage)
+            }
+            _ => self.compact(storage),
+        }
+    }
+
+    fn append(&mut self, storage: &Storage) -> anyhow::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let file_name = self.journal_path();
+        let scope = self.scope();
+        let mut data = Vec::new();
+        for record in &self.pending {
+            data.extend(state::encode_record(record, &scope, &storage.key)?);
+        }
         let mut f = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
+            .append(true)
             .create(true)
             .open(&file_name)
             .with_context(|| format!("failed to open file: {}", file_name.display()))?;
+        f.write_all(&data)?;
+        f.sync_data()?;
+        if self.journaled == Some(0) {
+            /* The journal was just created. */
+            sync_parent(&file_name)?;
+        }
+
+        self.journaled = self.journaled.map(|n| n + self.pending.len());
+        self.pending.clear();
+        Ok(())
+    }
 
-        bincode::serialize_into(&mut f, &BinaryState::from_voting(self))?;
+    /// Writes a new snapshot and renames it over the current one, so that a crash leaves either
+    /// of them. Records of a journal left behind are already in the snapshot and skipped on load.
+    fn compact(&mut self, storage: &Storage) -> anyhow::Result<()> {
+        let file_name =
+            Self::storage_path(&self.contract, &self.voting_id, &self.operator_address());
+        let tmp_name = file_name.with_extension("bin.tmp");
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .open(&tmp_name)
+            .with_context(|| format!("failed to open file: {}", tmp_name.display()))?;
 
+        f.write_all(&state::encode(
+            &BinaryState::from_voting(self),
+            &storage.key,
+        )?)?;
+        f.sync_all()?;
+        fs::rename(&tmp_name, &file_name)
+            .with_context(|| format!("failed to replace {}", file_name.display()))?;
+        sync_parent(&file_name)?;
+
+        let journal = self.journal_path();
+        if journal.exists() {
+            fs::remove_file(&journal)
+                .with_context(|| format!("failed to remove {}", journal.display()))?;
+        }
+        self.journaled = Some(0);
+        self.pending.clear();
         Ok(())
     }
 
     pub fn load(
-        _requested_contract: &str,
+        requested_contract: &str,
         requested_voting_id: &str,
-        _operator_addr: &str,
+        operator_addr: &str,
+        storage: &Storage,
     ) -> anyhow::Result<Self> {
-        let file_name = Self::storage_path(requested_voting_id);
-        let mut f = BufReader::new(
+        let contract = EthAddress::from_hex(requested_contract)
+            .with_context(|| format!("invalid contract {}", requested_contract))?;
+        let operator = EthAddress::from_hex(operator_addr)
+            .with_context(|| format!("invalid operator address {}", operator_addr))?;
+        let mut file_name = Self::storage_path(&contract, requested_voting_id, &operator);
+        if !file_name.exists() {
+            /* State saved before elections were scoped by contract and operator. */
+            let legacy = super::prv_path(format!("voting-{}.bin", requested_voting_id));
+            if legacy.exists() {
+                file_name = legacy;
+            }
+        }
+
+        let (mut voting, version) = Self::read(&file_name, storage, true)?;
+        voting
+            .check(&contract, requested_voting_id, &operator)
+            .with_context(|| format!("stored state {} does not match", file_name.display()))?;
+        if version < state::VERSION {
+            voting.compact(storage).context("migrating state")?;
+            if file_name != Self::storage_path(&contract, requested_voting_id, &operator) {
+                fs::remove_file(&file_name)?;
+            }
+        }
+        Ok(voting)
+    }
+
+    /// Lists stored elections, optionally only those of `contract`.
+    pub fn list(contract: Option<&str>, storage: &Storage) -> anyhow::Result<Vec<Summary>> {
+        let contract = contract
+            .map(|contract| {
+                EthAddress::from_hex(contract)
+                    .with_context(|| format!("invalid contract {}", contract))
+            })
+            .transpose()?;
+        let mut summaries: Vec<Summary> = Vec::new();
+        for entry in fs::read_dir(super::prv_path("")).context("failed to list stored state")? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            if !name.starts_with("voting-") || !name.ends_with(".bin") {
+                continue;
+            }
+            let (v, _) = Self::read(&path, storage, false)?;
+            if matches!(&contract, Some(contract) if *contract != v.contract) {
+                continue;
+            }
+            let operator = v.operator_address();
+            /* A legacy file may have been saved again under its scoped name. */
+            if summaries.iter().any(|s| {
+                s.contract == v.contract && s.voting_id == v.voting_id && s.operator == operator
+            }) {
+                continue;
+            }
+            let votes = v.voters.values().filter(|(_, voted)| *voted).count();
+            summaries.push(Summary {
+                contract: v.contract,
+                voting_id: v.voting_id,
+                operator,
+                started: v.started,
+                closed: v.closed,
+                voters: v.voters.len(),
+                votes,
+            });
+        }
+        summaries.sort_by(|a, b| {
+            (a.contract.to_array(), &a.voting_id).cmp(&(b.contract.to_array(), &b.voting_id))
+        });
+        Ok(summaries)
+    }
+
+    /// Reads a snapshot of any supported version and replays the journal. The version of the
+    /// snapshot is returned along with the state. With `repair`, the record of an interrupted
+    /// append is dropped from the journal; files are not modified otherwise.
+    fn read(file_name: &Path, storage: &Storage, repair: bool) -> anyhow::Result<(Self, u16)> {
+        let data = fs::read(file_name)
+            .with_context(|| format!("failed to open file {}", file_name.display()))?;
+        let (state, version) = state::decode(&data, &storage.key, storage.allow_unsealed)
+            .with_context(|| format!("invalid state {}", file_name.display()))?;
+        let mut voting = state.into_voting()?;
+
+        let journal = voting.journal_path();
+        if !journal.exists() {
+            return Ok((voting, version));
+        }
+        let data = fs::read(&journal)
+            .with_context(|| format!("failed to open file {}", journal.display()))?;
+        let (records, partial) = state::decode_records(&data, &voting.scope(), &storage.key)
+            .with_context(|| format!("invalid journal {}", journal.display()))?;
+        for record in &records {
+            if record.seq <= voting.seq {
+                continue;
+            }
+            if record.seq != voting.seq + 1 {
+                return Err(VotingError::InvalidState)
+                    .with_context(|| format!("records missing in {}", journal.display()));
+            }
+            voting.apply(&record.op)?;
+            voting.seq = record.seq;
+        }
+        voting.journaled = Some(records.len());
+        if repair && partial > 0 {
+            /* Drop the record of an interrupted append, so that new records follow valid ones. */
             fs::OpenOptions::new()
-                .read(true)
-                .open(&file_name)
-                .with_context(|| format!("failed to open file {}", file_name.display()))?,
-        );
+                .write(true)
+                .open(&journal)?
+                .set_len((data.len() - partial) as u64)?;
+        }
+        Ok((voting, version))
+    }
+
+    /// Applies `op` and queues it for the journal.
+    fn record(&mut self, op: Operation) -> Result<(), VotingError> {
+        self.apply(&op)?;
+        self.seq += 1;
+        self.pending.push(Record { seq: self.seq, op });
+        Ok(())
+    }
+
+    fn apply(&mut self, op: &Operation) -> Result<(), VotingError> {
+        match op {
+            Operation::Register { voter, session_key } => {
+                let session_key = PublicKey::parse_slice(session_key, None)
+                    .map_err(|_| VotingError::InvalidState)?;
+                self.voters
+                    .insert(EthAddress::new(*voter), (session_key, false));
+            }
+            Operation::Start => self.started = true,
+            Operation::Vote {
+                voter,
+                option,
+                ballot,
+            } => {
+                if self.policy == VotePolicy::LastVoteWins {
+                    self.ballots.insert((*voter, *ballot));
+                }
+                let voter = EthAddress::new(*voter);
+                let (_, voted) = self
+                    .voters
+                    .get_mut(&voter)
+                    .ok_or(VotingError::InvalidAddress)?;
+                *voted = true;
+                if self.policy == VotePolicy::LastVoteWins {
+                    if let Some(previous) = self.choices.insert(voter, *option) {
+                        if let hash_map::Entry::Occupied(mut e) = self.results.entry(previous) {
+                            *e.get_mut() -= 1;
+                            if *e.get() == 0 {
+                                e.remove();
+                            }
+                        }
+                    }
+                }
+                *self.results.entry(*option).or_insert(0) += 1;
+            }
+            Operation::Report | Operation::Close => self.closed = true,
+            Operation::Time { now } => self.time = *now,
+        }
+        Ok(())
+    }
+
+    /// Checks that `deadline` has not passed at the time of the current operation.
+    fn check_deadline(&self, deadline: Option<u64>) -> Result<(), VotingError> {
+        match (deadline, self.now) {
+            (None, _) => Ok(()),
+            (Some(_), None) => Err(VotingError::InvalidTime),
+            (Some(deadline), Some(now)) if now > deadline => Err(VotingError::DeadlinePassed),
+            _ => Ok(()),
+        }
+    }
+
+    /// Merkle root of eligible voters if given, otherwise Keccak-256 of the sorted eligible
+    /// addresses, each padded to 32 bytes as by `abi.encodePacked(address[])`. Zero if anyone may
+    /// register.
+    fn eligible_hash(&self) -> [u8; 32] {
+        if let Some(root) = self.eligible_root {
+            return root;
+        }
+        let mut hash = [0; 32];
+        if let Some(eligible) = &self.eligible {
+            let words: Vec<_> = sorted_addresses(eligible)
+                .iter()
+                .map(|address| [&[0u8; 12][..], address].concat())
+                .collect();
+            hash.copy_from_slice(EthHash::from_parts(&words).as_ref());
+        }
+        hash
+    }
+
+    /// Messages a voter may sign to register `session`: the EIP-712 typed data
+    /// `SgxRegister(string votingId,address operator,address session)` if the chain is known,
+    /// and the legacy personal message.
+    fn registration_hashes(&self, session: &EthAddress) -> Vec<EthHash> {
+        let mut hashes = Vec::with_capacity(2);
+        if let Some(chain_id) = self.chain_id {
+            let domain = eth::Eip712Domain {
+                name: DOMAIN_NAME,
+                version: DOMAIN_VERSION,
+                chain_id,
+                verifying_contract: &self.contract,
+            };
+            let message =
+                EthHash::new("SgxRegister(string votingId,address operator,address session)")
+                    .add(eth::signature_hash(&self.voting_id))
+                    .add(eth::address_word(&self.operator_address()))
+                    .add(eth::address_word(session))
+                    .build();
+            hashes.push(EthHash::typed_data(&domain, &message));
+        }
+        hashes.push(EthHash::personal_message(format!(
+            "\nSgxRegister\nContract: {contract:x} {voting_id}\nAddress: {operator:x}\nSession: {session:x}",
+            contract = self.contract,
+            voting_id = self.voting_id,
+            operator = self.operator_address(),
+            session = session
+        )));
+        hashes
+    }
+
+    /// Hash signed in the receipt of the ballot of `sender`, accepted as journal record `seq`.
+    fn receipt_hash(&self, sender: &EthAddress, ballot_hash: &[u8; 32], seq: u64) -> EthHash {
+        EthHash::new("SgxVotingReceipt(address,bytes,address,bytes32,uint64)")
+            .add(&self.contract)
+            .add(&self.voting_id)
+            .add(sender)
+            .add(ballot_hash)
+            .add(eth::uint_word(seq))
+            .build()
+    }
+
+    /// Binds a ballot to the election and its sender.
+    fn ballot_aad(&self, version: u8, sender: &EthAddress) -> Vec<u8> {
+        [
+            &[version][..],
+            &self.contract.to_array(),
+            self.voting_id.as_bytes(),
+            &sender.to_array(),
+            &self.operator_address().to_array(),
+        ]
+        .concat()
+    }
+
+    /// Cipher of a voter session for ballots of `version`. Keys of the current version are
+    /// derived for the `info` direction, salted with the election; legacy ballots use the shared
+    /// secret itself.
+    fn session_cipher(&self, shared_sec: &[u8], version: u8, info: &[u8]) -> Aes256Gcm {
+        if version < BALLOT_VERSION {
+            return Aes256Gcm::new(GenericArray::from_slice(shared_sec));
+        }
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&self.scope()), shared_sec)
+            .expand(info, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Aes256Gcm::new(GenericArray::from_slice(&key))
+    }
+
+    /// Identifies the election in journal records and in ballot keys.
+    fn scope(&self) -> Vec<u8> {
+        [
+            &self.contract.to_array()[..],
+            &self.operator_address().to_array(),
+            self.voting_id.as_bytes(),
+        ]
+        .concat()
+    }
+
+    fn journal_path(&self) -> PathBuf {
+        Self::storage_path(&self.contract, &self.voting_id, &self.operator_address())
+            .with_extension("journal")
+    }
 
-        let state: BinaryState = bincode::deserialize_from(&mut f)?;
-        Ok(state.into_voting()?)
+    /// Verifies that this is the election of `contract`, `voting_id` run by `operator`.
+    fn check(
+        &self,
+        contract: &EthAddress,
+        voting_id: &str,
+        operator: &EthAddress,
+    ) -> Result<(), VotingError> {
+        if self.contract != *contract || self.voting_id != voting_id {
+            return Err(VotingError::InvalidId);
+        }
+        if self.operator_address() != *operator {
+            return Err(VotingError::InvalidAddress);
+        }
+        Ok(())
     }
 
-    fn storage_path(voting_id: &str) -> PathBuf {
-        super::prv_path(format!("voting-{}.bin", voting_id))
+    /// State of each election is kept in a separate file. The voting id is hex encoded, as it
+    /// is an arbitrary string.
+    fn storage_path(contract: &EthAddress, voting_id: &str, operator: &EthAddress) -> PathBuf {
+        super::prv_path(format!(
+            "voting-{:x}-{}-{:x}.bin",
+            contract,
+            hex::encode(voting_id),
+            operator
+        ))
     }
 }
 
 impl Voting {
+    /// Sets the time of the current operation, which may not precede the last accepted one.
+    pub fn set_time(&mut self, now: u64) -> Result<(), VotingError> {
+        if now < self.time {
+            return Err(VotingError::InvalidTime);
+        }
+        if now > self.time {
+            self.record(Operation::Time { now })?;
+        }
+        self.now = Some(now);
+        Ok(())
+    }
+
     pub fn start(&mut self) -> Result<String, VotingError> {
         if self.started {
             return Err(VotingError::AlreadyStarted);
         }
-        self.started = true;
+        self.record(Operation::Start)?;
         let voters = self
             .voters
             .keys()
@@ -178,44 +754,71 @@ impl Voting {
         sender: &str,
         signature_hex: &str,
         session_pub_key: &str,
+        proof: &[[u8; 32]],
     ) -> anyhow::Result<String> {
-        use crate::eth;
         if self.started {
             return Err(VotingError::AlreadyStarted.into());
         }
+        self.check_deadline(self.registration_deadline)?;
         let sender = eth::EthAddress::from_hex(sender)
             .with_context(|| format!("invalid sender {}", sender))?;
+        if matches!(&self.eligible, Some(eligible) if !eligible.contains(&sender)) {
+            return Err(VotingError::NotEligible.into());
+        }
+        if let Some(root) = &self.eligible_root {
+            if !eth::verify_merkle_proof(root, eth::merkle_leaf(&sender), proof) {
+                return Err(VotingError::NotEligible).context("invalid Merkle proof");
+            }
+        }
         let signature = RecoverableSignature::from_hex(signature_hex)
             .with_context(|| format!("invalid signature format, {:?}", signature_hex))?;
         let session_pub_key = PublicKey::parse_slice(&hex::decode(&session_pub_key)?, None)?;
 
-        let mesagage_hash = EthHash::personal_message(
-            format!("\nSgxRegister\nContract: {contract:x} {voting_id}\nAddress: {operator:x}\nSession: {session_addr:x}",
-                contract= self.contract,
-                voting_id = self.voting_id,
-                operator = self.operator_address(),
-                session_addr = session_pub_key.to_eth_address()
-            ),
-        );
-        if signature.recover_pub_key(&mesagage_hash)?.to_eth_address() != sender {
+        let signed = self
+            .registration_hashes(&session_pub_key.to_eth_address())
+            .iter()
+            .any(|hash| {
+                matches!(signature.recover_pub_key(hash), Ok(key) if key.to_eth_address() == sender)
+            });
+        if !signed {
             return Err(VotingError::InvalidAddress).context("invalid signature address");
         }
-        let hash = EthHash::new("SgxVotingTicket(address,bytes,address)")
-            .add(&self.contract)
-            .add(&self.voting_id)
-            .add(&sender)
-            .build();
-        self.voters.insert(sender, (session_pub_key, false));
+        /* The root lets the contract check that tickets were issued for the same voters. */
+        let hash = match &self.eligible_root {
+            None => EthHash::new("SgxVotingTicket(address,bytes,address)")
+                .add(&self.contract)
+                .add(&self.voting_id)
+                .add(&sender),
+            Some(root) => EthHash::new("SgxVotingTicket(address,bytes,address,bytes32)")
+                .add(&self.contract)
+                .add(&self.voting_id)
+                .add(&sender)
+                .add(root),
+        }
+        .build();
+        self.record(Operation::Register {
+            voter: sender.to_array(),
+            session_key: session_pub_key.serialize().to_vec(),
+        })?;
 
         let signature = hash.sign_by(&self.secret);
 
         Ok(signature.to_hex())
     }
 
-    pub fn vote(&mut self, sender: &str, encrypted_vote: &str) -> anyhow::Result<String> {
+    pub fn vote(
+        &mut self,
+        sender: &str,
+        encrypted_vote: &str,
+        allow_legacy_ballots: bool,
+    ) -> anyhow::Result<Receipt> {
         if !self.started {
             return Err(VotingError::NotStarted.into());
         }
+        if self.closed {
+            return Err(VotingError::Closed.into());
+        }
+        self.check_deadline(self.voting_deadline)?;
 
         let sender_addr = EthAddress::from_hex(sender)?;
 
@@ -223,67 +826,99 @@ impl Voting {
             .voters
             .get(&sender_addr)
             .ok_or(VotingError::InvalidAddress)?;
-        if *voted_already {
+        if *voted_already && self.policy == VotePolicy::Single {
             return Err(VotingError::AlreadyVoted.into());
         }
 
-        let vote = hex::decode(encrypted_vote)?;
-        if vote.len() <= 12 {
-            return Err(VotingError::DecryptionError.into());
-        }
+        let ballot = hex::decode(encrypted_vote)?;
 
         let shared_sec = secp256k1::SharedSecret::<Sha256>::new(session_key, &self.secret)?;
-        let shared_key = GenericArray::from_slice(shared_sec.as_ref());
-        let cipher = Aes256Gcm::new(&shared_key);
-
-        let nonce = GenericArray::from_slice(&vote[..12]);
-        let enc_vote = &vote[12..];
-
-        let vote = cipher
-            .decrypt(nonce, enc_vote)
-            .map_err(|e| anyhow::anyhow!("DecryptionError {}", e))
-            .with_context(|| format!("fail to decrypt vote"))?;
-        if vote.len() != 4 {
-            return Err(VotingError::DecryptionError).context("invalid vote length");
-        }
-
-        let vote = u32::from_le_bytes(vote.as_slice().try_into()?);
-
-        *self.results.entry(vote).or_insert(0) += 1;
+        let shared_sec = shared_sec.as_ref();
 
-        match self.voters.entry(sender_addr) {
-            hash_map::Entry::Occupied(mut e) => {
-                e.get_mut().1 = true;
+        let opened = match ballot.split_first() {
+            Some((&version, data)) if version == BALLOT_VERSION => {
+                let cipher = self.session_cipher(shared_sec, version, BALLOT_INFO);
+                open_ballot(&cipher, data, &self.ballot_aad(version, &sender_addr))
+                    .map(|vote| (vote, version))
             }
-            hash_map::Entry::Vacant(_) => {
-                panic!("impossible");
+            _ => Err(VotingError::DecryptionError).context("unsupported ballot version"),
+        };
+        /* Legacy ballots have no version byte, so they may start with any byte. */
+        let (vote, version) = match opened {
+            Err(e) if allow_legacy_ballots => {
+                let cipher = self.session_cipher(shared_sec, 0, BALLOT_INFO);
+                open_ballot(&cipher, &ballot, &[])
+                    .map(|vote| (vote, 0))
+                    .map_err(|_| e)
             }
+            opened => opened,
+        }?;
+
+        let mut ballot_hash = [0; 32];
+        ballot_hash.copy_from_slice(EthHash::from_parts(&[&ballot]).as_ref());
+        if self
+            .ballots
+            .contains(&(sender_addr.to_array(), ballot_hash))
+        {
+            return Err(VotingError::ReplayedBallot.into());
         }
+        self.record(Operation::Vote {
+            voter: sender_addr.to_array(),
+            option: vote,
+            ballot: ballot_hash,
+        })?;
         let response = b"ACCEPTED";
         let mut rng = WasiRng::default();
         let mut iv = [0u8; 12];
         rng.fill_bytes(&mut iv);
+        let cipher = self.session_cipher(shared_sec, version, RECEIPT_INFO);
         let encrypted_response = cipher
             .encrypt(&GenericArray::from(iv), response.as_ref())
             .map_err(|e| anyhow::anyhow!("EncryptionError: {}", e))?;
 
-        Ok(format!(
-            "{}{}",
-            hex::encode(&iv),
-            hex::encode(&encrypted_response)
-        ))
+        let signature = self
+            .receipt_hash(&sender_addr, &ballot_hash, self.seq)
+            .sign_by(&self.secret);
+        Ok(Receipt {
+            response: [&iv[..], &encrypted_response].concat(),
+            ballot_hash,
+            seq: self.seq,
+            signature: signature.to_hex(),
+        })
     }
 
-    pub fn report(&self) -> anyhow::Result<(Vec<(u32, u32)>, String)> {
+    /// Closes voting, after which no votes are accepted.
+    pub fn close(&mut self) -> Result<(), VotingError> {
+        if !self.started {
+            return Err(VotingError::NotStarted);
+        }
+        if !self.closed {
+            self.record(Operation::Close)?;
+        }
+        Ok(())
+    }
+
+    /// Signs the results along with the number of abstentions and the hash of eligible voters,
+    /// once voting is closed or its deadline passed, and the quorum is met.
+    pub fn report(&mut self) -> anyhow::Result<Report> {
         if !self.started {
             return Err(VotingError::NotStarted.into());
         }
+        let deadline_passed = matches!(
+            self.check_deadline(self.voting_deadline),
+            Err(VotingError::DeadlinePassed)
+        );
+        if !self.closed && !deadline_passed {
+            return Err(VotingError::NotFinished).context("voting is not closed");
+        }
 
-        for (_, (_, voted)) in self.voters.iter() {
-            if !voted {
-                return Err(VotingError::NotFinished.into());
-            }
+        let votes = self.voters.values().filter(|(_, voted)| *voted).count();
+        let required = self.quorum.required(self.voters.len());
+        if votes < required {
+            return Err(VotingError::NotFinished)
+                .with_context(|| format!("{} of {} required votes", votes, required));
         }
+        let abstentions = (self.voters.len() - votes) as u32;
 
         let mut results = Vec::with_capacity(self.results.len());
 
@@ -292,22 +927,584 @@ impl Voting {
         }
         results.sort_by(|(k1, _), (k2, _)| Ord::cmp(k1, k2));
 
-        let mut hasher = EthHash::new("SgxVotingResults(address,string,fixed32[])")
+        let eligible_hash = self.eligible_hash();
+        let mut hasher = EthHash::new("SgxVotingResults(address,string,uint32,bytes32,fixed32[])")
             .add(&self.contract)
-            .add(&self.voting_id);
+            .add(&self.voting_id)
+            .add(eth::uint_word(abstentions.into()))
+            .add(eligible_hash);
         for (k, v) in &results {
             hasher = hasher.add(u32::to_le_bytes(*k)).add(u32::to_le_bytes(*v))
         }
         let signature = hasher.build().sign_by(&self.secret);
+        self.record(Operation::Report)?;
+
+        Ok(Report {
+            results,
+            abstentions,
+            eligible_hash,
+            signature: signature.to_hex(),
+        })
+    }
+}
+
+/// Syncs the directory of `file_name`, so that creating or renaming it is durable.
+fn sync_parent(file_name: &Path) -> anyhow::Result<()> {
+    let dir = file_name.parent().unwrap_or_else(|| Path::new("."));
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("failed to sync {}", dir.display()))
+}
+
+fn sorted_addresses(addresses: &HashSet<EthAddress>) -> Vec<[u8; 20]> {
+    let mut addresses: Vec<_> = addresses.iter().map(EthAddress::to_array).collect();
+    addresses.sort();
+    addresses
+}
 
-        Ok((results, signature.to_hex()))
+/// Decrypts the vote of a ballot without the version byte.
+fn open_ballot(cipher: &Aes256Gcm, data: &[u8], aad: &[u8]) -> anyhow::Result<u32> {
+    if data.len() <= 12 {
+        return Err(VotingError::DecryptionError.into());
+    }
+    let (nonce, msg) = data.split_at(12);
+    let vote = cipher
+        .decrypt(GenericArray::from_slice(nonce), Payload { msg, aad })
+        .map_err(|e| anyhow::anyhow!("DecryptionError {}", e))
+        .context("fail to decrypt vote")?;
+    if vote.len() != 4 {
+        return Err(VotingError::DecryptionError).context("invalid vote length");
     }
+    Ok(u32::from_le_bytes(vote.as_slice().try_into()?))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_scope() -> anyhow::Result<()> {
+        let contract = EthAddress::from_hex("c73b910e58cb19341ec86111a054547d536d0448")?;
+        let other = EthAddress::new([1; 20]);
+        let v = Voting::new(
+            EthAddress::new(contract.to_array()),
+            "1".into(),
+            Params::default(),
+        );
+        let operator = v.operator_address();
+
+        assert!(v.check(&contract, "1", &operator).is_ok());
+        assert!(v.check(&other, "1", &operator).is_err());
+        assert!(v.check(&contract, "2", &operator).is_err());
+        assert!(v.check(&contract, "1", &other).is_err());
+
+        let path = Voting::storage_path(&contract, "../1", &operator);
+        assert_eq!(path.parent(), Some(Path::new("/private")));
+        assert_ne!(path, Voting::storage_path(&other, "../1", &operator));
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay() -> anyhow::Result<()> {
+        let mut v = Voting::new(EthAddress::new([1; 20]), "1".into(), Params::default());
+        let snapshot = BinaryState::from_voting(&v);
+        let session_key = PublicKey::from_secret_key(&SecretKey::random(&mut WasiRng));
+        v.record(Operation::Register {
+            voter: [2; 20],
+            session_key: session_key.serialize().to_vec(),
+        })?;
+        v.record(Operation::Start)?;
+        v.record(Operation::Vote {
+            voter: [2; 20],
+            option: 3,
+            ballot: [5; 32],
+        })?;
+        assert!(v
+            .record(Operation::Vote {
+                voter: [4; 20],
+                option: 3,
+                ballot: [6; 32],
+            })
+            .is_err());
+        assert_eq!(v.pending.len(), 3);
+
+        let mut replayed = snapshot.into_voting()?;
+        for record in &v.pending {
+            replayed.apply(&record.op)?;
+            replayed.seq = record.seq;
+        }
+        assert_eq!(
+            BinaryState::from_voting(&replayed),
+            BinaryState::from_voting(&v)
+        );
+        assert_eq!(replayed.seq, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_revote() -> anyhow::Result<()> {
+        let vote = |voter: u8, option: u32| Operation::Vote {
+            voter: [voter; 20],
+            option,
+            ballot: [option as u8; 32],
+        };
+        for policy in &[VotePolicy::Single, VotePolicy::LastVoteWins] {
+            let mut v = Voting::new(
+                EthAddress::new([1; 20]),
+                "1".into(),
+                Params {
+                    policy: *policy,
+                    ..Params::default()
+                },
+            );
+            let session_key = PublicKey::from_secret_key(&SecretKey::random(&mut WasiRng));
+            for voter in &[[2; 20], [3; 20]] {
+                v.record(Operation::Register {
+                    voter: *voter,
+                    session_key: session_key.serialize().to_vec(),
+                })?;
+            }
+            v.start()?;
+            v.record(vote(2, 1))?;
+            v.record(vote(3, 1))?;
+            v.record(vote(2, 2))?;
+
+            let mut results: Vec<_> = v.results.iter().map(|(k, v)| (*k, *v)).collect();
+            results.sort();
+            match policy {
+                VotePolicy::Single => {
+                    assert_eq!(results, vec![(1, 2), (2, 1)]);
+                    assert!(v.vote(&"02".repeat(20), "", false).is_err());
+                }
+                VotePolicy::LastVoteWins => {
+                    assert_eq!(results, vec![(1, 1), (2, 1)]);
+                    v.record(vote(3, 2))?;
+                    assert_eq!(v.results.get(&1), None);
+                    assert_eq!(v.results.get(&2), Some(&2));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_quorum() -> anyhow::Result<()> {
+        assert_eq!("3".parse::<Quorum>()?, Quorum::Votes(3));
+        assert_eq!("60%".parse::<Quorum>()?, Quorum::Percent(60));
+        assert!("101%".parse::<Quorum>().is_err());
+        assert!("-1".parse::<Quorum>().is_err());
+        assert_eq!(Quorum::Percent(60).required(4), 3);
+        assert_eq!(Quorum::Percent(100).required(4), 4);
+
+        /* Three registered voters, the first `votes` of which voted for option 1. */
+        let election = |votes: usize| -> anyhow::Result<Voting> {
+            let mut v = Voting::new(
+                EthAddress::new([1; 20]),
+                "1".into(),
+                Params {
+                    quorum: Quorum::Votes(2),
+                    ..Params::default()
+                },
+            );
+            let mut sessions = Vec::new();
+            for voter in 2..5 {
+                let voter = SecretKey::parse(&[voter; 32])?;
+                let session = SecretKey::random(&mut WasiRng);
+                let session_key = PublicKey::from_secret_key(&session);
+                let signature = v.registration_hashes(&session_key.to_eth_address())[0]
+                    .sign_by(&voter)
+                    .to_hex();
+                let sender = voter.to_eth_address();
+                v.register(
+                    &sender.to_hex_string(),
+                    &signature,
+                    &hex::encode(session_key.serialize().as_ref()),
+                    &[],
+                )?;
+                sessions.push((sender, session));
+            }
+            v.start()?;
+            for (sender, session) in sessions.iter().take(votes) {
+                let operator_key = PublicKey::parse(&v.operator_pubkey())?;
+                let shared_sec = secp256k1::SharedSecret::<Sha256>::new(&operator_key, session)?;
+                let nonce = [7u8; 12];
+                let ct = v
+                    .session_cipher(shared_sec.as_ref(), BALLOT_VERSION, BALLOT_INFO)
+                    .encrypt(
+                        GenericArray::from_slice(&nonce),
+                        Payload {
+                            msg: &1u32.to_le_bytes(),
+                            aad: &v.ballot_aad(BALLOT_VERSION, sender),
+                        },
+                    )
+                    .map_err(|e| anyhow::anyhow!("{}", e))?;
+                let ballot = [&[BALLOT_VERSION][..], &nonce, &ct].concat();
+                v.vote(&sender.to_hex_string(), &hex::encode(ballot), false)?;
+            }
+            Ok(v)
+        };
+        for votes in 0..2 {
+            let mut v = election(votes)?;
+            v.close()?;
+            assert!(matches!(
+                v.report().unwrap_err().downcast_ref(),
+                Some(VotingError::NotFinished)
+            ));
+        }
+
+        let mut v = election(2)?;
+        /* The quorum is met, but voters may still vote. */
+        assert!(matches!(
+            v.report().unwrap_err().downcast_ref(),
+            Some(VotingError::NotFinished)
+        ));
+        v.close()?;
+        let report = v.report()?;
+        assert_eq!(report.results, vec![(1, 2)]);
+        assert_eq!(report.abstentions, 1);
+        let mut abstentions = [0; 32];
+        abstentions[31] = 1;
+        let hash = EthHash::from_parts(&[
+            eth::signature_hash("SgxVotingResults(address,string,uint32,bytes32,fixed32[])")
+                .as_ref(),
+            &[1; 20],
+            b"1",
+            &abstentions,
+            &report.eligible_hash,
+            &[1, 0, 0, 0, 2, 0, 0, 0],
+        ]);
+        let signer = RecoverableSignature::from_hex(&report.signature)?.recover_pub_key(&hash)?;
+        assert_eq!(signer.to_eth_address(), v.operator_address());
+        assert!(matches!(
+            v.vote(&"04".repeat(20), "", false)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(VotingError::Closed)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deadlines() -> anyhow::Result<()> {
+        let mut v = Voting::new(
+            EthAddress::new([1; 20]),
+            "1".into(),
+            Params {
+                registration_deadline: Some(100),
+                voting_deadline: Some(200),
+                ..Params::default()
+            },
+        );
+        assert!(matches!(
+            v.register("", "", "", &[]).unwrap_err().downcast_ref(),
+            Some(VotingError::InvalidTime)
+        ));
+        v.set_time(50)?;
+        v.set_time(50)?;
+        assert!(matches!(v.set_time(49), Err(VotingError::InvalidTime)));
+        assert!(v.check_deadline(v.registration_deadline).is_ok());
+        v.set_time(150)?;
+        assert!(matches!(
+            v.register("", "", "", &[]).unwrap_err().downcast_ref(),
+            Some(VotingError::DeadlinePassed)
+        ));
+        assert!(v.close().is_err());
+        v.start()?;
+        assert!(v.check_deadline(v.voting_deadline).is_ok());
+        v.set_time(250)?;
+        assert!(matches!(
+            v.vote("", "", false).unwrap_err().downcast_ref(),
+            Some(VotingError::DeadlinePassed)
+        ));
+        v.close()?;
+        v.close()?;
+        assert!(matches!(
+            v.vote("", "", false).unwrap_err().downcast_ref(),
+            Some(VotingError::Closed)
+        ));
+        assert_eq!(BinaryState::from_voting(&v).time, 250);
+        assert_eq!(v.pending.len(), 5);
+
+        /* Results may be reported once the voting deadline passed, without closing. */
+        let mut v = Voting::new(
+            EthAddress::new([1; 20]),
+            "1".into(),
+            Params {
+                voting_deadline: Some(200),
+                quorum: Quorum::Votes(0),
+                ..Params::default()
+            },
+        );
+        v.start()?;
+        v.set_time(200)?;
+        assert!(matches!(
+            v.report().unwrap_err().downcast_ref(),
+            Some(VotingError::NotFinished)
+        ));
+        v.set_time(201)?;
+        v.report()?;
+        assert!(v.closed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eligible() -> anyhow::Result<()> {
+        let eligible = || Some(vec![EthAddress::new([3; 20]), EthAddress::new([2; 20])]);
+        let mut v = Voting::new(
+            EthAddress::new([1; 20]),
+            "1".into(),
+            Params {
+                eligible: eligible(),
+                ..Params::default()
+            },
+        );
+        let error = |v: &mut Voting, sender: u8| {
+            v.register(&hex::encode([sender; 20]), "", "", &[])
+                .unwrap_err()
+                .downcast::<VotingError>()
+                .ok()
+        };
+        assert!(matches!(error(&mut v, 4), Some(VotingError::NotEligible)));
+        assert!(error(&mut v, 2).is_none());
+
+        /* abi.encodePacked([address(0x0202..), address(0x0303..)]) */
+        let packed = hex::decode(format!("{:0>64}{:0>64}", "02".repeat(20), "03".repeat(20)))?;
+        assert_eq!(&v.eligible_hash(), EthHash::from_parts(&[packed]).as_ref());
+        let state = BinaryState::from_voting(&v);
+        assert_eq!(state.eligible, Some(vec![[2; 20], [3; 20]]));
+        assert_eq!(state.into_voting()?.eligible_hash(), v.eligible_hash());
+
+        let v = Voting::new(EthAddress::new([1; 20]), "1".into(), Params::default());
+        assert_eq!(v.eligible_hash(), [0; 32]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_eligible_root() -> anyhow::Result<()> {
+        let leaves: Vec<_> = (2..4)
+            .map(|i| eth::merkle_leaf(&EthAddress::new([i; 20])))
+            .collect();
+        let (a, b) = (leaves[0].min(leaves[1]), leaves[0].max(leaves[1]));
+        let mut root = [0; 32];
+        root.copy_from_slice(EthHash::from_parts(&[a, b]).as_ref());
+        let mut v = Voting::new(
+            EthAddress::new([1; 20]),
+            "1".into(),
+            Params {
+                eligible_root: Some(root),
+                ..Params::default()
+            },
+        );
+        let error = |v: &mut Voting, sender: u8, proof: &[[u8; 32]]| {
+            v.register(&hex::encode([sender; 20]), "", "", proof)
+                .unwrap_err()
+                .downcast::<VotingError>()
+                .ok()
+        };
+        assert!(matches!(
+            error(&mut v, 2, &[]),
+            Some(VotingError::NotEligible)
+        ));
+        assert!(matches!(
+            error(&mut v, 4, &[leaves[1]]),
+            Some(VotingError::NotEligible)
+        ));
+        assert!(error(&mut v, 2, &[leaves[1]]).is_none());
+        assert!(error(&mut v, 3, &[leaves[0]]).is_none());
+
+        assert_eq!(v.eligible_hash(), root);
+        let state = BinaryState::from_voting(&v);
+        assert_eq!(state.eligible_root, Some(root));
+        assert_eq!(state.into_voting()?.eligible_hash(), root);
+        Ok(())
+    }
+
+    #[test]
+    fn test_register() -> anyhow::Result<()> {
+        let mut v = Voting::new(
+            EthAddress::new([1; 20]),
+            "1".into(),
+            Params {
+                chain_id: Some(4),
+                ..Params::default()
+            },
+        );
+        let voter = SecretKey::random(&mut WasiRng);
+        let sender = voter.to_eth_address().to_hex_string();
+        let session = PublicKey::from_secret_key(&SecretKey::random(&mut WasiRng));
+        let session_hex = hex::encode(session.serialize().as_ref());
+        let hashes = v.registration_hashes(&session.to_eth_address());
+        assert_eq!(hashes.len(), 2);
+
+        let other = SecretKey::random(&mut WasiRng);
+        let forged = hashes[0].sign_by(&other).to_hex();
+        assert!(v.register(&sender, &forged, &session_hex, &[]).is_err());
+        for hash in &hashes {
+            let signature = hash.sign_by(&voter).to_hex();
+            v.register(&sender, &signature, &session_hex, &[])?;
+        }
+
+        /* Without the chain, only the personal message is accepted. */
+        v.chain_id = None;
+        let typed = hashes[0].sign_by(&voter).to_hex();
+        assert!(v.register(&sender, &typed, &session_hex, &[]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ballot() -> anyhow::Result<()> {
+        let mut v = Voting::new(EthAddress::new([1; 20]), "1".into(), Params::default());
+        let session_secret = SecretKey::random(&mut WasiRng);
+        let session_key = PublicKey::from_secret_key(&session_secret);
+        for voter in &[[2; 20], [3; 20], [4; 20]] {
+            v.record(Operation::Register {
+                voter: *voter,
+                session_key: session_key.serialize().to_vec(),
+            })?;
+        }
+        v.start()?;
+
+        let shared_sec = secp256k1::SharedSecret::<Sha256>::new(
+            &PublicKey::from_secret_key(&v.secret),
+            &session_secret,
+        )?;
+        let seal = |version: u8, aad: &[u8]| -> anyhow::Result<Vec<u8>> {
+            let nonce = [7u8; 12];
+            let ct = v
+                .session_cipher(shared_sec.as_ref(), version, BALLOT_INFO)
+                .encrypt(
+                    GenericArray::from_slice(&nonce),
+                    Payload {
+                        msg: &5u32.to_le_bytes(),
+                        aad,
+                    },
+                )
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            Ok([&nonce[..], &ct].concat())
+        };
+        let ballot = |version: u8, voter: u8| -> anyhow::Result<Vec<u8>> {
+            let aad = v.ballot_aad(version, &EthAddress::new([voter; 20]));
+            Ok([vec![version], seal(version, &aad)?].concat())
+        };
+        let current = ballot(BALLOT_VERSION, 2)?;
+        let legacy = seal(0, &[])?;
+        /* Versioned ballots under the shared secret itself are no longer accepted. */
+        let v2 = ballot(2, 3)?;
+        let mut downgraded = current.clone();
+        downgraded[0] = 2;
+
+        /* Bound to the sender. */
+        assert!(v
+            .vote(&"03".repeat(20), &hex::encode(&current), true)
+            .is_err());
+        assert!(v
+            .vote(&"02".repeat(20), &hex::encode(&legacy), false)
+            .is_err());
+        assert!(v
+            .vote(&"02".repeat(20), &hex::encode(&downgraded), true)
+            .is_err());
+        assert!(v.vote(&"03".repeat(20), &hex::encode(&v2), true).is_err());
+        let receipt = v.vote(&"02".repeat(20), &hex::encode(&current), false)?;
+        v.vote(&"04".repeat(20), &hex::encode(&legacy), true)?;
+        assert_eq!(v.results.get(&5), Some(&2));
+
+        assert_eq!(
+            &receipt.ballot_hash[..],
+            EthHash::from_parts(&[&current]).as_ref()
+        );
+        assert_eq!(receipt.seq, 5);
+        let signer = RecoverableSignature::from_hex(&receipt.signature)?.recover_pub_key(
+            &v.receipt_hash(&EthAddress::new([2; 20]), &receipt.ballot_hash, receipt.seq),
+        )?;
+        assert_eq!(signer.to_eth_address(), v.operator_address());
+        let response = v
+            .session_cipher(shared_sec.as_ref(), BALLOT_VERSION, RECEIPT_INFO)
+            .decrypt(
+                GenericArray::from_slice(&receipt.response[..12]),
+                &receipt.response[12..],
+            )
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        assert_eq!(response, b"ACCEPTED");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replayed_ballot() -> anyhow::Result<()> {
+        let mut v = Voting::new(
+            EthAddress::new([1; 20]),
+            "1".into(),
+            Params {
+                policy: VotePolicy::LastVoteWins,
+                ..Params::default()
+            },
+        );
+        let session_secret = SecretKey::random(&mut WasiRng);
+        v.record(Operation::Register {
+            voter: [2; 20],
+            session_key: PublicKey::from_secret_key(&session_secret)
+                .serialize()
+                .to_vec(),
+        })?;
+        v.start()?;
+
+        let shared_sec = secp256k1::SharedSecret::<Sha256>::new(
+            &PublicKey::from_secret_key(&v.secret),
+            &session_secret,
+        )?;
+        let cipher = v.session_cipher(shared_sec.as_ref(), BALLOT_VERSION, BALLOT_INFO);
+        let aad = v.ballot_aad(BALLOT_VERSION, &EthAddress::new([2; 20]));
+        let ballot = |option: u32| -> anyhow::Result<String> {
+            let mut nonce = [0u8; 12];
+            WasiRng.fill_bytes(&mut nonce);
+            let ct = cipher
+                .encrypt(
+                    GenericArray::from_slice(&nonce),
+                    Payload {
+                        msg: &option.to_le_bytes(),
+                        aad: &aad,
+                    },
+                )
+                .map_err(|e| anyhow::anyhow!("{}", e))?;
+            Ok(hex::encode([&[BALLOT_VERSION][..], &nonce, &ct].concat()))
+        };
+        let first = ballot(1)?;
+        v.vote(&"02".repeat(20), &first, false)?;
+        v.vote(&"02".repeat(20), &ballot(2)?, false)?;
+        assert!(matches!(
+            v.vote(&"02".repeat(20), &first, false)
+                .unwrap_err()
+                .downcast_ref(),
+            Some(VotingError::ReplayedBallot)
+        ));
+        assert_eq!(v.results.get(&1), None);
+        assert_eq!(v.results.get(&2), Some(&1));
+
+        /* Remembered across a snapshot. */
+        let mut v = BinaryState::from_voting(&v).into_voting()?;
+        assert!(v.vote(&"02".repeat(20), &first, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ui_ballot() -> anyhow::Result<()> {
+        let mut v = Voting::new(EthAddress::new([1; 20]), "1".into(), Params::default());
+        v.secret = SecretKey::parse(&[0x11; 32])?;
+        let session_secret = SecretKey::parse(&[0x22; 32])?;
+        v.record(Operation::Register {
+            voter: [2; 20],
+            session_key: PublicKey::from_secret_key(&session_secret)
+                .serialize()
+                .to_vec(),
+        })?;
+        v.start()?;
+
+        /* encryptBallot of ui/sgx-voting-app/src/ballot.js, voting 5 as 0x0202..02 */
+        let ballot = "037dc6c4c4106d644a01ed14f4ceba67e33b67963257ce17fd2a0cb3602889baf2";
+        v.vote(&"02".repeat(20), ballot, false)?;
+        assert_eq!(v.results.get(&5), Some(&1));
+        Ok(())
+    }
+
     #[test]
     fn test_decrypt() -> anyhow::Result<()> {
         let key = hex::decode("ba95ff8fdf43418d6653a1bfd542c5ef1c840892c0381ec1ebd89cf8bd29731b")?;
@@ -39,9 +39,15 @@ async fn new_session(
         .with(async {
             Ok::<_, actix_web::Error>(
                 HttpResponse::Ok().json(
-                    session::new_session(&api_session, &spec, &args.subnet, "sgx")
-                        .await
-                        .map_err(actix_web::error::ErrorInternalServerError)?,
+                    session::new_session(
+                        &api_session,
+                        &spec,
+                        &args.subnet,
+                        "sgx",
+                        &args.sealing_key_file,
+                    )
+                    .await
+                    .map_err(actix_web::error::ErrorInternalServerError)?,
                 ),
             )
         })
@@ -89,11 +95,14 @@ async fn send_vote(path: web::Path<(String, String)>, mut vote: web::Payload) ->
     }
     log::debug!("recived vote: {} bytes ", bytes.len());
 
-    let response = session::send_vote(manager_addr, sender, bytes).await?;
+    let receipt = session::send_vote(manager_addr, sender, bytes).await?;
     Ok::<_, actix_web::Error>(
         HttpResponse::Ok()
             .content_type("application/octet-stream")
-            .body(response),
+            .header("X-Receipt-Seq", receipt.seq)
+            .header("X-Receipt-Ballot-Hash", receipt.ballot_hash)
+            .header("X-Receipt-Signature", receipt.signature)
+            .body(receipt.response),
     )
 }
 
@@ -121,6 +130,13 @@ struct Args {
     subnet: String,
     #[structopt(long, env = "YAGNA_APPKEY")]
     appkey: String,
+    /// Sealing key of the voting manager state, as seen in the enclave
+    #[structopt(
+        long,
+        env = "VOTING_SEALING_KEY_FILE",
+        default_value = "/dev/attestation/keys/_sgx_mrenclave"
+    )]
+    sealing_key_file: String,
 }
 
 #[actix_web::main]
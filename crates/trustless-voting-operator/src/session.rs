@@ -42,6 +42,10 @@ pub struct NewSession {
     pub min_voters: usize,
     pub registration_deadline: Option<DateTime<Utc>>,
     pub voting_deadline: Option<DateTime<Utc>>,
+    /// Merkle root of eligible voters, who then register with a proof.
+    pub eligible_root: Option<String>,
+    /// Chain of the contract, for registrations signed as EIP-712 typed data.
+    pub chain_id: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -51,6 +55,9 @@ pub struct NewVoter {
     sender: String,
     sign: String,
     session_key: String,
+    /// Merkle proof of the sender being eligible, if the session has an eligible root.
+    #[serde(default)]
+    proof: Vec<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -59,6 +66,8 @@ pub struct SessionInfo {
     pub contract: String,
     pub voting_id: String,
     pub manager_address: String,
+    /// Merkle root of eligible voters, signed along in registration tickets.
+    pub eligible_root: Option<String>,
     pub state: State,
 }
 
@@ -68,6 +77,7 @@ pub struct SessionDetails {
     pub contract: String,
     pub voting_id: String,
     pub manager_address: String,
+    pub eligible_root: Option<String>,
     pub state: State,
     pub tickets: BTreeMap<String, String>,
     pub credentials: ya_client::model::activity::Credentials,
@@ -87,9 +97,12 @@ pub enum State {
         voting_deadline: DateTime<Utc>,
         voters: Vec<Voter>,
     },
+    #[serde(rename_all = "camelCase")]
     Report {
         voters: Vec<String>,
         votes: BTreeMap<u32, u32>,
+        abstentions: u32,
+        eligible_hash: String,
         signature: String,
     },
 }
@@ -105,12 +118,39 @@ pub async fn new_session(
     spec: &NewSession,
     subnet: &str,
     runtime: &str,
+    sealing_key_file: &str,
 ) -> anyhow::Result<SessionInfo> {
-    let deadline = Utc::now()
-        + chrono::Duration::from_std(DEFAULT_REGISTRATION_TIME)?
-        + chrono::Duration::from_std(DEFAULT_VOTING_TIME)?;
+    let now = Utc::now();
+    let registration_deadline = match spec.registration_deadline {
+        Some(deadline) => deadline,
+        None => now + chrono::Duration::from_std(DEFAULT_REGISTRATION_TIME)?,
+    };
+    let voting_deadline = match spec.voting_deadline {
+        Some(deadline) => deadline,
+        None => registration_deadline + chrono::Duration::from_std(DEFAULT_VOTING_TIME)?,
+    };
+    let mut args = vec![
+        "--sealing-key-file".to_string(),
+        sealing_key_file.to_string(),
+        "--time".to_string(),
+        now.timestamp().to_string(),
+        "init".to_string(),
+        spec.contract.clone(),
+        spec.voting_id.clone(),
+        "--registration-deadline".to_string(),
+        registration_deadline.timestamp().to_string(),
+        "--voting-deadline".to_string(),
+        voting_deadline.timestamp().to_string(),
+    ];
+    if let Some(root) = &spec.eligible_root {
+        args.extend(vec!["--eligible-root".to_string(), root.clone()]);
+    }
+    if let Some(chain_id) = spec.chain_id {
+        args.extend(vec!["--chain-id".to_string(), chain_id.to_string()]);
+    }
     let agreement =
-        crate::market::create_agreement(api_session.market()?, subnet, runtime, deadline).await?;
+        crate::market::create_agreement(api_session.market()?, subnet, runtime, voting_deadline)
+            .await?;
     let activity = api_session.create_secure_activity(&agreement).await?;
 
     let output = {
@@ -120,11 +160,7 @@ pub async fn new_session(
                 rest::ExeScriptCommand::Start { args: vec![] },
                 rest::ExeScriptCommand::Run {
                     entry_point: ENYTY_POINT.to_string(),
-                    args: vec![
-                        "init".to_string(),
-                        spec.contract.clone(),
-                        spec.voting_id.clone(),
-                    ],
+                    args,
                 },
             ])
             .await?;
@@ -157,13 +193,11 @@ pub async fn new_session(
                 (Some("OK"), Some(addr), _) => addr,
                 _ => anyhow::bail!("failed to initialize voting manager ({:?})", output),
             };
-            let now = Utc::now();
-            let registration_deadline =
-                now + chrono::Duration::from_std(DEFAULT_REGISTRATION_TIME)?;
             let info = SessionInfo {
                 contract: spec.contract.clone(),
                 voting_id: spec.voting_id.clone(),
                 manager_address: addr.to_string(),
+                eligible_root: spec.eligible_root.clone(),
                 state: State::Init {
                     min_voters: spec.min_voters,
                     registration_deadline,
@@ -175,6 +209,8 @@ pub async fn new_session(
             let session_ref = Session {
                 info,
                 tickets,
+                voting_deadline,
+                sealing_key_file: sealing_key_file.to_string(),
                 activity,
             }
             .start();
@@ -192,6 +228,9 @@ pub async fn new_session(
 pub struct Session {
     info: SessionInfo,
     tickets: BTreeMap<String, String>,
+    voting_deadline: DateTime<Utc>,
+    /// Passed to every manager command, so that its state is sealed by the enclave.
+    sealing_key_file: String,
     activity: SgxActivity,
 }
 
@@ -202,6 +241,10 @@ impl Session {
         command_args: Vec<String>,
     ) -> impl Future<Output = anyhow::Result<String>> + 'static {
         let mut args = vec![
+            "--sealing-key-file".to_string(),
+            self.sealing_key_file.clone(),
+            "--time".to_string(),
+            Utc::now().timestamp().to_string(),
             command.to_string(),
             self.info.contract.clone(),
             self.info.voting_id.clone(),
@@ -272,6 +315,7 @@ impl Handler<GetDetails> for Session {
             contract: self.info.contract.clone(),
             voting_id: self.info.voting_id.clone(),
             manager_address: self.info.manager_address.clone(),
+            eligible_root: self.info.eligible_root.clone(),
             state: self.info.state.clone(),
             credentials: self.activity.credentials().unwrap(),
             tickets,
@@ -312,13 +356,12 @@ impl Handler<NewVoter> for Session {
 
     fn handle(&mut self, msg: NewVoter, _ctx: &mut Self::Context) -> Self::Result {
         let sender = msg.sender;
-        let out = self
-            .exec_command(
-                "register",
-                vec![sender.clone(), msg.sign.clone(), msg.session_key.clone()],
-            )
-            .into_actor(self)
-            .then(move |output: anyhow::Result<_>, act, _ctx| {
+        let mut args = vec![sender.clone(), msg.sign.clone(), msg.session_key.clone()];
+        for node in msg.proof {
+            args.extend(vec!["--proof".to_string(), node]);
+        }
+        let out = self.exec_command("register", args).into_actor(self).then(
+            move |output: anyhow::Result<_>, act, _ctx| {
                 log::debug!("got register command result: {:?}", output);
                 fut::ready((|| -> anyhow::Result<_> {
                     let output = output?;
@@ -338,7 +381,8 @@ impl Handler<NewVoter> for Session {
                     let _ = act.tickets.insert(sender, ticket.clone());
                     Ok(ticket)
                 })())
-            });
+            },
+        );
         Box::pin(out)
     }
 }
@@ -370,8 +414,7 @@ impl Handler<Start> for Session {
                         })
                         .collect();
                     let new_state = State::Voting {
-                        voting_deadline: Utc::now()
-                            + chrono::Duration::from_std(DEFAULT_VOTING_TIME)?,
+                        voting_deadline: act.voting_deadline,
                         voters,
                     };
                     act.info.state = new_state;
@@ -396,42 +439,69 @@ impl Handler<Finish> for Session {
             State::Voting { .. } => true,
             _ => return ActorResponse::reply(Ok(self.info.clone())),
         };
-        ActorResponse::r#async(self.exec_command("report", vec![]).into_actor(self).then(
-            |output, act, _ctx| {
-                fut::result((|| {
-                    let output = output?;
-                    let mut it = parse_output(&output)?;
-                    let signature = it
-                        .next()
-                        .ok_or_else(|| anyhow::anyhow!("missing signature"))?
-                        .to_string();
-                    let mut votes = BTreeMap::new();
-                    while let (Some(key), Some(value)) = (it.next(), it.next()) {
-                        let k = u32::from_str_radix(key, 16)?;
-                        let v = u32::from_str_radix(value, 16)?;
-                        let _ = votes.insert(k, v);
-                    }
-                    let new_state = State::Report {
-                        voters: Default::default(),
-                        votes,
-                        signature,
-                    };
-                    act.info.state = new_state;
-                    Ok(act.info.clone())
-                })())
-            },
-        ))
+        /* The manager reports only once voting is closed. */
+        let closed = self.exec_command("close", vec![]).into_actor(self);
+        ActorResponse::r#async(
+            closed
+                .then(|closed, act, _ctx| {
+                    let report = closed.map(|_| act.exec_command("report", vec![]));
+                    async move { report?.await }.into_actor(act)
+                })
+                .then(|output, act, _ctx| {
+                    fut::result((|| {
+                        let output = output?;
+                        let mut it = parse_output(&output)?;
+                        let signature = it
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("missing signature"))?
+                            .to_string();
+                        let abstentions = it
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("missing abstentions"))?;
+                        let abstentions = u32::from_str_radix(abstentions, 16)?;
+                        let eligible_hash = it
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("missing eligible voters hash"))?
+                            .to_string();
+                        let mut votes = BTreeMap::new();
+                        while let (Some(key), Some(value)) = (it.next(), it.next()) {
+                            let k = u32::from_str_radix(key, 16)?;
+                            let v = u32::from_str_radix(value, 16)?;
+                            let _ = votes.insert(k, v);
+                        }
+                        let new_state = State::Report {
+                            voters: Default::default(),
+                            votes,
+                            abstentions,
+                            eligible_hash,
+                            signature,
+                        };
+                        act.info.state = new_state;
+                        Ok(act.info.clone())
+                    })())
+                }),
+        )
     }
 }
 
 pub struct NewVote(String, Vec<u8>);
 
+/// Response of the manager to an accepted vote.
+pub struct VoteReceipt {
+    /// Encrypted to the voter session.
+    pub response: Vec<u8>,
+    pub seq: String,
+    pub ballot_hash: String,
+    /// Signature of the manager over the election, the voter, the ballot hash and `seq`.
+    pub signature: String,
+}
+
 impl Message for NewVote {
-    type Result = anyhow::Result<Vec<u8>>;
+    type Result = anyhow::Result<VoteReceipt>;
 }
 
 impl Handler<NewVote> for Session {
-    type Result = ResponseFuture<anyhow::Result<Vec<u8>>>;
+    type Result = ResponseFuture<anyhow::Result<VoteReceipt>>;
 
     fn handle(&mut self, msg: NewVote, _ctx: &mut Self::Context) -> Self::Result {
         // TODO reject invalid state
@@ -448,10 +518,16 @@ impl Handler<NewVote> for Session {
         async move {
             let raw_output = batch.await?;
             let mut output = parse_output(&raw_output)?;
-            if let Some(result) = output.next() {
-                Ok(hex::decode(result)?)
-            } else {
-                Err(anyhow::anyhow!("Invalid response: {:?}", raw_output))
+            match (output.next(), output.next(), output.next(), output.next()) {
+                (Some(response), Some(seq), Some(ballot_hash), Some(signature)) => {
+                    Ok(VoteReceipt {
+                        response: hex::decode(response)?,
+                        seq: seq.to_string(),
+                        ballot_hash: ballot_hash.to_string(),
+                        signature: signature.to_string(),
+                    })
+                }
+                _ => Err(anyhow::anyhow!("Invalid response: {:?}", raw_output)),
             }
         }
         .boxed_local()
@@ -530,7 +606,7 @@ pub async fn send_vote(
     manager_addr: String,
     sender: String,
     vote: Vec<u8>,
-) -> actix_web::Result<Vec<u8>> {
+) -> actix_web::Result<VoteReceipt> {
     get_session_actor(manager_addr)
         .await?
         .send(NewVote(sender, vote))
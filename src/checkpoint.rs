@@ -0,0 +1,255 @@
+//! Checkpoints of workdir volumes, used to migrate long-running services between providers.
+//!
+//! A checkpoint is a tar archive of the selected volumes, prefixed with a header. When a sealing
+//! key is configured the archive is encrypted and authenticated with AES-256-GCM, so that it can
+//! only be restored by an enclave holding the same key.
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes256Gcm,
+};
+use rand::RngCore;
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+/// Entry point of the built-in checkpoint command, `@checkpoint <output> <volume>...`.
+pub const COMMAND: &str = "@checkpoint";
+
+const MAGIC: &[u8; 8] = b"YACKPT01";
+const PLAIN: u8 = 0;
+const SEALED: u8 = 1;
+const HEADER_LEN: usize = 9;
+const NONCE_LEN: usize = 12;
+
+pub struct SealingKey([u8; 32]);
+
+impl SealingKey {
+    /// Reads a key stored either as 32 raw bytes or as 64 hex digits.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read(path)?;
+        let mut key = [0u8; 32];
+        if content.len() == key.len() {
+            key.copy_from_slice(&content);
+        } else {
+            hex::decode_to_slice(String::from_utf8_lossy(&content).trim(), &mut key).map_err(
+                |e| invalid_data(format!("invalid sealing key {}: {}", path.display(), e)),
+            )?;
+        }
+        Ok(SealingKey(key))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(GenericArray::from_slice(&self.0))
+    }
+}
+
+fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Volumes are given relative to the workdir and must stay inside it.
+pub fn check_volume(volume: &Path) -> io::Result<()> {
+    let valid = volume.components().next().is_some()
+        && volume
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid volume {}", volume.display()),
+        ))
+    }
+}
+
+pub fn checkpoint(
+    work_dir: &Path,
+    volumes: &[PathBuf],
+    key: Option<&SealingKey>,
+) -> io::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    for volume in volumes {
+        check_volume(volume)?;
+        builder.append_dir_all(volume, work_dir.join(volume))?;
+    }
+    let archive = builder.into_inner()?;
+
+    let mut header = MAGIC.to_vec();
+    match key {
+        None => {
+            header.push(PLAIN);
+            header.extend(archive);
+            Ok(header)
+        }
+        Some(key) => {
+            header.push(SEALED);
+            let mut nonce = [0u8; NONCE_LEN];
+            rand::thread_rng().fill_bytes(&mut nonce);
+            let sealed = key
+                .cipher()
+                .encrypt(
+                    GenericArray::from_slice(&nonce),
+                    Payload {
+                        msg: &archive,
+                        aad: &header,
+                    },
+                )
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "sealing checkpoint failed")
+                })?;
+            header.extend_from_slice(&nonce);
+            header.extend(sealed);
+            Ok(header)
+        }
+    }
+}
+
+/// Unpacks a checkpoint into `work_dir`. Volumes contained in the checkpoint must not exist
+/// or be empty. Without a key, plain checkpoints are restored only if `allow_unsealed`, as
+/// their content cannot be authenticated. Returns the restored volumes.
+pub fn restore(
+    work_dir: &Path,
+    data: &[u8],
+    key: Option<&SealingKey>,
+    allow_unsealed: bool,
+) -> io::Result<Vec<PathBuf>> {
+    if key.is_none() && !allow_unsealed {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "no sealing key is configured and unsealed checkpoints are not allowed",
+        ));
+    }
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != MAGIC {
+        return Err(invalid_data("not a checkpoint archive"));
+    }
+    let (header, body) = data.split_at(HEADER_LEN);
+    let archive = match (header[MAGIC.len()], key) {
+        (PLAIN, None) => body.to_vec(),
+        (PLAIN, Some(_)) => {
+            return Err(invalid_data(
+                "checkpoint is not sealed, but a sealing key is configured",
+            ))
+        }
+        (SEALED, Some(key)) if body.len() > NONCE_LEN => {
+            let (nonce, sealed) = body.split_at(NONCE_LEN);
+            key.cipher()
+                .decrypt(
+                    GenericArray::from_slice(nonce),
+                    Payload {
+                        msg: sealed,
+                        aad: header,
+                    },
+                )
+                .map_err(|_| invalid_data("checkpoint was modified or sealed with another key"))?
+        }
+        (SEALED, None) => {
+            return Err(invalid_data(
+                "checkpoint is sealed, but no key is configured",
+            ))
+        }
+        _ => return Err(invalid_data("invalid checkpoint header")),
+    };
+
+    let mut volumes = Vec::new();
+    for entry in tar::Archive::new(archive.as_slice()).entries()? {
+        let path = entry?.path()?.into_owned();
+        if let Some(Component::Normal(volume)) = path.components().next() {
+            let volume = PathBuf::from(volume);
+            if !volumes.contains(&volume) {
+                volumes.push(volume);
+            }
+        }
+    }
+    for volume in &volumes {
+        let path = work_dir.join(volume);
+        if path.exists() && fs::read_dir(&path)?.next().is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("volume {} is not empty", volume.display()),
+            ));
+        }
+    }
+    tar::Archive::new(archive.as_slice()).unpack(work_dir)?;
+    Ok(volumes)
+}
+
+/// Translates `@checkpoint <output> <volume>...` into an invocation of the runtime itself.
+pub fn self_invocation(
+    work_dir: &Path,
+    key_file: Option<&Path>,
+    args: Vec<String>,
+) -> io::Result<(String, Vec<String>)> {
+    let mut args = args.into_iter().skip(1);
+    let output = args
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "missing <output>"))?;
+    check_volume(Path::new(&output))?;
+    let exe = std::env::current_exe()?;
+
+    let mut self_args = vec![exe.display().to_string(), "--workdir".to_string()];
+    self_args.push(work_dir.display().to_string());
+    if let Some(key_file) = key_file {
+        self_args.push("--sealing-key-file".to_string());
+        self_args.push(key_file.display().to_string());
+    }
+    self_args.extend(vec![
+        "checkpoint".to_string(),
+        "--output".to_string(),
+        output,
+    ]);
+    for volume in args {
+        check_volume(Path::new(&volume))?;
+        self_args.push("--volume".to_string());
+        self_args.push(volume);
+    }
+    Ok((exe.display().to_string(), self_args))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checkpoint_restore() -> io::Result<()> {
+        let base = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        let (src, dst) = (base.join("src"), base.join("dst"));
+        fs::create_dir_all(src.join("private/sub"))?;
+        fs::create_dir_all(&dst)?;
+        fs::write(src.join("private/sub/key.bin"), b"secret")?;
+        fs::write(src.join("other"), b"other")?;
+
+        let key = SealingKey([7u8; 32]);
+        let sealed = checkpoint(&src, &["private".into()], Some(&key))?;
+        assert!(!sealed.windows(6).any(|w| w == b"secret"));
+        assert!(restore(&dst, &sealed, None, true).is_err());
+        assert!(restore(&dst, &sealed, Some(&SealingKey([8u8; 32])), false).is_err());
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(restore(&dst, &tampered, Some(&key), false).is_err());
+
+        assert_eq!(
+            restore(&dst, &sealed, Some(&key), false)?,
+            vec![PathBuf::from("private")]
+        );
+        assert_eq!(fs::read(dst.join("private/sub/key.bin"))?, b"secret");
+        assert!(!dst.join("other").exists());
+        assert!(restore(&dst, &sealed, Some(&key), false).is_err());
+
+        let plain = checkpoint(&src, &["private".into()], None)?;
+        assert!(restore(&base.join("other"), &plain, Some(&key), true).is_err());
+        let err = restore(&base.join("plain"), &plain, None, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!base.join("plain/private").exists());
+        assert_eq!(
+            restore(&base.join("plain"), &plain, None, true)?,
+            vec![PathBuf::from("private")]
+        );
+        assert!(checkpoint(&src, &["../src".into()], None).is_err());
+
+        fs::remove_dir_all(&base)
+    }
+}
@@ -0,0 +1,133 @@
+//! Workdir cleanup performed after shutdown.
+use serde::Deserialize;
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CleanupPolicy {
+    /// Leave the workdir untouched.
+    Keep,
+    /// Remove the workdir contents.
+    Delete,
+    /// Overwrite files in private volumes, then remove the workdir contents.
+    Wipe,
+}
+
+impl FromStr for CleanupPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(CleanupPolicy::Keep),
+            "delete" => Ok(CleanupPolicy::Delete),
+            "wipe" => Ok(CleanupPolicy::Wipe),
+            _ => Err(format!(
+                "invalid cleanup policy {:?}, expected one of: keep, delete, wipe",
+                s
+            )),
+        }
+    }
+}
+
+/// Applies `policy` to `work_dir`. `private_volumes` are relative to `work_dir`.
+pub fn cleanup(
+    work_dir: &Path,
+    policy: CleanupPolicy,
+    private_volumes: &[PathBuf],
+) -> io::Result<()> {
+    match policy {
+        CleanupPolicy::Keep => return Ok(()),
+        CleanupPolicy::Delete => (),
+        CleanupPolicy::Wipe => {
+            for volume in private_volumes {
+                let path = work_dir.join(volume);
+                if path.exists() {
+                    wipe(&path)?;
+                }
+            }
+        }
+    }
+    for entry in fs::read_dir(work_dir)? {
+        let path = entry?.path();
+        if path.symlink_metadata()?.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Overwrites all regular files under `path` with zeros. Symbolic links are not followed.
+///
+/// This does not defeat copy-on-write filesystems or SSD wear leveling, but keeps the plain
+/// contents from lingering in the provider's page cache and on simple block devices.
+fn wipe(path: &Path) -> io::Result<()> {
+    let meta = path.symlink_metadata()?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            wipe(&entry?.path())?;
+        }
+    } else if meta.is_file() {
+        /* The file may have been replaced since the check above: a link is not followed and
+         * only a regular file is overwritten. Non-blocking, so that a FIFO is not waited on. */
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+            .open(path)?;
+        let meta = f.metadata()?;
+        if !meta.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a regular file", path.display()),
+            ));
+        }
+        let zeros = [0u8; 8192];
+        let mut remaining = meta.len();
+        while remaining > 0 {
+            let n = remaining.min(zeros.len() as u64) as usize;
+            f.write_all(&zeros[..n])?;
+            remaining -= n as u64;
+        }
+        f.sync_all()?;
+        log::debug!("wiped {}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cleanup() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("workdir-cleanup-{}", std::process::id()));
+        fs::create_dir_all(dir.join("private"))?;
+        fs::write(dir.join("private/key.bin"), b"secret")?;
+        fs::write(dir.join("output"), b"output")?;
+        let outside = dir.with_extension("outside");
+        fs::write(&outside, b"outside")?;
+        std::os::unix::fs::symlink(&outside, dir.join("private/link"))?;
+
+        cleanup(&dir, CleanupPolicy::Keep, &[])?;
+        assert!(dir.join("output").exists());
+
+        wipe(&dir.join("private"))?;
+        assert_eq!(fs::read(dir.join("private/key.bin"))?, vec![0u8; 6]);
+        assert_eq!(fs::read(&outside)?, b"outside");
+
+        cleanup(&dir, CleanupPolicy::Wipe, &["private".into()])?;
+        assert!(dir.exists());
+        assert_eq!(fs::read_dir(&dir)?.count(), 0);
+        assert_eq!(fs::read(&outside)?, b"outside");
+
+        fs::remove_file(&outside)?;
+        fs::remove_dir(&dir)
+    }
+}
@@ -0,0 +1,465 @@
+//! Runtime configuration file.
+//!
+//! ```toml
+//! [limits]
+//! workdir-quota = "512M"
+//! quota-check-interval = 5
+//! max-processes = 8
+//!
+//! [timeouts]
+//! shutdown = 5
+//!
+//! [allowlist]
+//! binaries = ["/work/trustless-voting-mgr"]
+//! verify-manifest = "/work/ya-runtime-sgx.manifest.sgx"
+//!
+//! [env]
+//! clear = true
+//! vars = { RUST_LOG = "info" }
+//!
+//! [output]
+//! capture = true
+//! buffer-size = "64K"
+//! log-files = true
+//! log-file-size = "10M"
+//! log-file-count = 3
+//!
+//! [network]
+//! isolation = "none"
+//! packages = { "trustless-voting-mgr.ywasi" = "namespace" }
+//!
+//! [cleanup]
+//! policy = "wipe"
+//! private-volumes = ["private"]
+//!
+//! [service]
+//! restart = "on-failure"
+//! backoff-initial = 1
+//! backoff-max = 60
+//! max-restarts = 10
+//!
+//! [checkpoint]
+//! key-file = "/protected/checkpoint.key"
+//! allow-unsealed = false
+//!
+//! [logging]
+//! level = "debug"
+//! format = "json"
+//! ```
+use crate::{
+    cleanup::CleanupPolicy,
+    logging::LogFormat,
+    network::NetworkIsolation,
+    quota,
+    service::{Backoff, RestartPolicy},
+};
+use serde::{Deserialize, Deserializer};
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Config {
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub allowlist: Allowlist,
+    pub env: Environment,
+    pub output: Output,
+    pub network: Network,
+    pub cleanup: Cleanup,
+    pub service: Service,
+    pub checkpoint: Checkpoint,
+    pub logging: Logging,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Limits {
+    /// Maximum size of files in the workdir, in bytes or with a unit suffix, e.g. `"512M"`.
+    #[serde(deserialize_with = "deserialize_optional_size")]
+    pub workdir_quota: Option<u64>,
+    /// Interval between workdir usage scans, in seconds.
+    pub quota_check_interval: u64,
+    /// Maximum number of concurrently running processes.
+    pub max_processes: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            workdir_quota: None,
+            quota_check_interval: 5,
+            max_processes: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Timeouts {
+    /// Time given to killed processes to be reaped on shutdown, in seconds.
+    pub shutdown: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts { shutdown: 5 }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Allowlist {
+    /// Executables allowed to be run. Any executable is allowed if empty.
+    pub binaries: Vec<PathBuf>,
+    /// Signed manifest whose trusted file checksums are verified before running a process.
+    pub verify_manifest: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Environment {
+    /// Do not pass the runtime environment to processes.
+    pub clear: bool,
+    pub vars: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Output {
+    /// Report process stdout and stderr in the final process status.
+    pub capture: bool,
+    /// Number of trailing bytes of each stream reported in the process status.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub buffer_size: u64,
+    /// Write the whole output to `stdout.log` and `stderr.log` in the workdir.
+    pub log_files: bool,
+    /// Size at which log files are rotated.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub log_file_size: u64,
+    /// Number of rotated log files kept.
+    pub log_file_count: usize,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            capture: false,
+            buffer_size: 64 << 10,
+            log_files: false,
+            log_file_size: 10 << 20,
+            log_file_count: 3,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Network {
+    /// Isolation of processes started from packages not listed in `packages`.
+    pub isolation: NetworkIsolation,
+    /// Isolation by task package file name.
+    pub packages: BTreeMap<String, NetworkIsolation>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Cleanup {
+    pub policy: CleanupPolicy,
+    /// Workdir subdirectories overwritten before removal with the `wipe` policy.
+    pub private_volumes: Vec<PathBuf>,
+}
+
+impl Default for Cleanup {
+    fn default() -> Self {
+        Cleanup {
+            policy: CleanupPolicy::Keep,
+            private_volumes: Vec::new(),
+        }
+    }
+}
+
+/// Supervision of services. The daemon entry point is declared by the task package.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Service {
+    pub restart: RestartPolicy,
+    /// Delay before the first restart, in seconds, doubled on each consecutive restart.
+    pub backoff_initial: u64,
+    /// Maximum delay between restarts, in seconds.
+    pub backoff_max: u64,
+    /// Consecutive restarts after which the service is given up.
+    pub max_restarts: Option<u32>,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Service {
+            restart: RestartPolicy::default(),
+            backoff_initial: 1,
+            backoff_max: 60,
+            max_restarts: None,
+        }
+    }
+}
+
+impl Service {
+    pub fn backoff(&self) -> Backoff {
+        Backoff {
+            initial: Duration::from_secs(self.backoff_initial),
+            max: Duration::from_secs(self.backoff_max),
+            max_restarts: self.max_restarts,
+        }
+    }
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Checkpoint {
+    /// Key sealing checkpoints, 32 bytes either raw or hex encoded. Checkpoints are not
+    /// encrypted if unset.
+    pub key_file: Option<PathBuf>,
+    /// Whether checkpoints may be restored without a key. Unsealed checkpoints are not
+    /// authenticated, so anyone able to supply one controls the restored volumes.
+    pub allow_unsealed: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields, default)]
+pub struct Logging {
+    /// Default `env_logger` filter, overridden by `RUST_LOG`.
+    pub level: String,
+    /// `text` or `json`, one object per line.
+    pub format: LogFormat,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: "error".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+/// Size in bytes or with a unit suffix, e.g. `"512M"`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    fn bytes<E: serde::de::Error>(self) -> Result<u64, E> {
+        match self {
+            Size::Bytes(n) => Ok(n),
+            Size::Text(s) => quota::parse_size(&s).map_err(E::custom),
+        }
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(d: D) -> Result<u64, D::Error> {
+    Size::deserialize(d)?.bytes()
+}
+
+fn deserialize_optional_size<'de, D: Deserializer<'de>>(d: D) -> Result<Option<u64>, D::Error> {
+    Option::<Size>::deserialize(d)?.map(Size::bytes).transpose()
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("reading config {} failed: {}", path.display(), e),
+            )
+        })?;
+        toml::from_str(&content).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid config {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Checks constraints not expressed by the types.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.limits.workdir_quota == Some(0) {
+            return Err("limits.workdir-quota must be greater than 0".into());
+        }
+        if self.limits.quota_check_interval == 0 {
+            return Err("limits.quota-check-interval must be greater than 0".into());
+        }
+        if self.limits.max_processes == Some(0) {
+            return Err("limits.max-processes must be greater than 0".into());
+        }
+        if self.output.log_files && self.output.log_file_size == 0 {
+            return Err("output.log-file-size must be greater than 0".into());
+        }
+        if self.timeouts.shutdown == 0 {
+            return Err("timeouts.shutdown must be greater than 0".into());
+        }
+        for name in self.env.vars.keys() {
+            if name.is_empty() || name.contains('=') || name.contains('\0') {
+                return Err(format!("env.vars: invalid variable name {:?}", name));
+            }
+        }
+        if self.service.backoff_initial == 0
+            || self.service.backoff_max < self.service.backoff_initial
+        {
+            return Err(
+                "service.backoff-max must not be less than backoff-initial, \
+                        which must be greater than 0"
+                    .into(),
+            );
+        }
+        for volume in &self.cleanup.private_volumes {
+            if !volume
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+            {
+                return Err(format!(
+                    "cleanup.private-volumes: {} must be relative to the workdir",
+                    volume.display()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn quota_check_interval(&self) -> Duration {
+        Duration::from_secs(self.limits.quota_check_interval)
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.timeouts.shutdown)
+    }
+
+    pub fn network_isolation(&self, task_package: Option<&Path>) -> NetworkIsolation {
+        task_package
+            .and_then(Path::file_name)
+            .and_then(|name| self.network.packages.get(name.to_str()?))
+            .copied()
+            .unwrap_or(self.network.isolation)
+    }
+
+    /// Directories searched for executables requested by name, as in the process environment.
+    pub fn search_path(&self) -> Option<OsString> {
+        match self.env.vars.get("PATH") {
+            Some(path) => Some(path.into()),
+            None if self.env.clear => None,
+            None => std::env::var_os("PATH"),
+        }
+    }
+
+    /// Whether the executable at `path`, canonical as resolved for running, may be run.
+    pub fn is_allowed(&self, path: &Path) -> bool {
+        self.allowlist.binaries.is_empty()
+            || self.allowlist.binaries.iter().any(|allowed| {
+                allowed == path || allowed.canonicalize().ok().as_deref() == Some(path)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exec;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn test_parse() -> Result<(), Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(
+            r#"
+            [limits]
+            workdir-quota = "1K"
+            [allowlist]
+            binaries = ["/work/app"]
+            [env]
+            vars = { RUST_LOG = "info" }
+            [network]
+            packages = { "voting.ywasi" = "seccomp" }
+            [cleanup]
+            policy = "wipe"
+            private-volumes = ["private"]
+            "#,
+        )?;
+        config.validate()?;
+        assert_eq!(config.limits.workdir_quota, Some(1024));
+        assert_eq!(config.limits.quota_check_interval, 5);
+        assert_eq!(config.cleanup.policy, CleanupPolicy::Wipe);
+        assert!(config.is_allowed(Path::new("/work/app")));
+        assert!(!config.is_allowed(Path::new("/bin/sh")));
+        assert_eq!(
+            config.network_isolation(Some(Path::new("/tmp/voting.ywasi"))),
+            NetworkIsolation::Seccomp
+        );
+        assert_eq!(config.network_isolation(None), NetworkIsolation::None);
+
+        let config: Config = toml::from_str(
+            r#"
+            [service]
+            restart = "always"
+            "#,
+        )?;
+        config.validate()?;
+        assert_eq!(config.service.restart, RestartPolicy::Always);
+        assert_eq!(config.service.backoff().max, Duration::from_secs(60));
+
+        let config: Config = toml::from_str("[limits]\nworkdir-quota = 4096")?;
+        assert_eq!(config.limits.workdir_quota, Some(4096));
+        let config: Config = toml::from_str("[output]\nbuffer-size = \"1M\"")?;
+        assert_eq!(config.output.buffer_size, 1 << 20);
+        assert_eq!(config.output.log_file_count, 3);
+        let config: Config = toml::from_str("[logging]\nformat = \"json\"")?;
+        assert_eq!(config.logging.format, LogFormat::Json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowlist() -> Result<(), Box<dyn std::error::Error>> {
+        let work_dir = std::env::temp_dir().join(format!("allowlist-{}", std::process::id()));
+        fs::create_dir_all(&work_dir)?;
+        fs::copy("/bin/true", work_dir.join("sh"))?;
+        let config = Config {
+            allowlist: Allowlist {
+                binaries: vec![work_dir.join("sh")],
+                verify_manifest: None,
+            },
+            ..Config::default()
+        };
+        let allowed = |bin: &str| -> io::Result<bool> {
+            let path = exec::resolve(bin, &work_dir, Some(OsStr::new("/bin")))?;
+            Ok(config.is_allowed(&exec::Executable::open(&path)?.path))
+        };
+        assert!(allowed("./sh")?);
+        /* A bare name runs the `sh` found in PATH, not the allowed one in the workdir. */
+        assert!(!allowed("sh")?);
+
+        fs::remove_dir_all(&work_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(toml::from_str::<Config>("[limits]\nquota = 1").is_err());
+        assert!(toml::from_str::<Config>("[cleanup]\npolicy = \"shred\"").is_err());
+
+        let config: Config = toml::from_str("[cleanup]\nprivate-volumes = [\"../etc\"]")?;
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("[timeouts]\nshutdown = 0")?;
+        assert!(config.validate().is_err());
+        let config: Config = toml::from_str("[service]\nbackoff-max = 0")?;
+        assert!(config.validate().is_err());
+        /* The entry point is declared by the package, not by the provider. */
+        assert!(toml::from_str::<Config>("[service]\nentry-point = \"a\"").is_err());
+        Ok(())
+    }
+}
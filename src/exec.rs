@@ -0,0 +1,107 @@
+//! Executables run by the runtime.
+//!
+//! A requested executable is resolved once and opened. With trusted-file verification, it is
+//! verified and run through the open descriptor, so that the file run is the file checked even if
+//! the workdir changes meanwhile.
+use std::{
+    ffi::OsStr,
+    fs, io,
+    os::unix::{fs::PermissionsExt, io::AsRawFd},
+    path::{Path, PathBuf},
+};
+
+/// Resolves `bin` the way `execvp` does from `work_dir`: names containing a slash relative to
+/// `work_dir`, bare names through the directories of `search_path`.
+pub fn resolve(bin: &str, work_dir: &Path, search_path: Option<&OsStr>) -> io::Result<PathBuf> {
+    if bin.contains('/') {
+        return Ok(work_dir.join(bin));
+    }
+    search_path
+        .into_iter()
+        .flat_map(std::env::split_paths)
+        .map(|dir| work_dir.join(dir).join(bin))
+        .find(|path| is_executable(path))
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in PATH", bin),
+            )
+        })
+}
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Executable opened to be verified and run.
+pub struct Executable {
+    /// Canonical path of the opened file.
+    pub path: PathBuf,
+    pub file: fs::File,
+}
+
+impl Executable {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = fs::File::open(path)?;
+        if !file.metadata()?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a regular file", path.display()),
+            ));
+        }
+        let path = fs::read_link(fd_path(&file))?;
+        Ok(Executable { path, file })
+    }
+
+    /// Program running the opened file, as `fexecve` does. Valid while `self` is alive.
+    ///
+    /// The descriptor is closed on exec, so scripts must be run through their interpreter.
+    pub fn program(&self) -> PathBuf {
+        fd_path(&self.file)
+    }
+}
+
+fn fd_path(file: &fs::File) -> PathBuf {
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_resolve() -> io::Result<()> {
+        let work_dir = Path::new("/work");
+        assert_eq!(resolve("./app", work_dir, None)?, Path::new("/work/app"));
+        assert_eq!(resolve("/bin/sh", work_dir, None)?, Path::new("/bin/sh"));
+        assert_eq!(
+            resolve("sh", work_dir, Some(OsStr::new("/nonexistent:/bin")))?,
+            Path::new("/bin/sh")
+        );
+        assert!(resolve("sh", work_dir, None).is_err());
+        assert!(resolve("app", work_dir, Some(OsStr::new("/nonexistent"))).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_executable() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("exec-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("app");
+        fs::copy("/bin/true", &path)?;
+        let exe = Executable::open(&path)?;
+        assert_eq!(exe.path, path.canonicalize()?);
+
+        /* Replaced after the check, the checked file is still the one run. */
+        fs::copy("/bin/false", dir.join("other"))?;
+        fs::rename(dir.join("other"), &path)?;
+        assert!(Command::new(exe.program()).status()?.success());
+        assert!(!Command::new(&path).status()?.success());
+
+        assert!(Executable::open(&dir).is_err());
+        fs::remove_dir_all(&dir)
+    }
+}
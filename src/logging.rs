@@ -0,0 +1,186 @@
+//! Runtime logs, written as text or as JSON lines for log aggregation.
+//!
+//! Records logged through a [`Span`] carry its fields, e.g. the pid and entry point of a process,
+//! along with the time elapsed since the span was opened.
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::{
+    cell::RefCell,
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    time::Instant,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Target of span records, so that they are filtered like the rest of the runtime.
+const TARGET: &str = "ya_runtime_sgx";
+
+thread_local! {
+    /* Fields of the span currently logging on this thread, read by the formatter. */
+    static FIELDS: RefCell<Map<String, Value>> = RefCell::new(Map::new());
+}
+
+/// Initializes `env_logger` with `level` as the default filter, overridden by `RUST_LOG`.
+pub fn init(level: &str, format: LogFormat) {
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(level));
+    builder.format(move |buf, record| {
+        let fields = FIELDS.with(|fields| fields.borrow().clone());
+        let timestamp = match format {
+            LogFormat::Json => buf.timestamp_millis().to_string(),
+            LogFormat::Text => buf.timestamp().to_string(),
+        };
+        write_line(buf, format, &timestamp, record, fields)
+    });
+    builder.init();
+}
+
+fn write_line<W: Write>(
+    buf: &mut W,
+    format: LogFormat,
+    timestamp: &str,
+    record: &log::Record,
+    fields: Map<String, Value>,
+) -> io::Result<()> {
+    match format {
+        LogFormat::Json => {
+            let mut line = Map::new();
+            line.insert("ts".into(), timestamp.into());
+            line.insert("level".into(), record.level().to_string().into());
+            line.insert("target".into(), record.target().into());
+            line.insert("msg".into(), record.args().to_string().into());
+            line.extend(fields);
+            writeln!(buf, "{}", Value::Object(line))
+        }
+        LogFormat::Text => {
+            write!(
+                buf,
+                "[{} {:5} {}] {}",
+                timestamp,
+                record.level(),
+                record.target(),
+                record.args()
+            )?;
+            for (key, value) in fields {
+                write!(buf, " {}={}", key, value)?;
+            }
+            writeln!(buf)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Span {
+    fields: Map<String, Value>,
+    started: Instant,
+}
+
+impl Span {
+    pub fn new(name: &str) -> Self {
+        let mut fields = Map::new();
+        fields.insert("span".into(), name.into());
+        Span {
+            fields,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn with<V: Into<Value>>(mut self, key: &str, value: V) -> Self {
+        self.fields.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn log(&self, level: log::Level, args: fmt::Arguments) {
+        if !log::log_enabled!(target: TARGET, level) {
+            return;
+        }
+        let mut fields = self.fields.clone();
+        fields.insert(
+            "elapsed_ms".into(),
+            (self.started.elapsed().as_millis() as u64).into(),
+        );
+        FIELDS.with(|f| *f.borrow_mut() = fields);
+        log::log!(target: TARGET, level, "{}", args);
+        FIELDS.with(|f| f.borrow_mut().clear());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn line(format: LogFormat) -> String {
+        let span = Span::new("process")
+            .with("entry", "/bin/cat")
+            .with("batch", 2)
+            .with("pid", 42);
+        let mut buf = Vec::new();
+        write_line(
+            &mut buf,
+            format,
+            "2020-06-01T12:00:00Z",
+            &log::Record::builder()
+                .level(log::Level::Info)
+                .target(TARGET)
+                .args(format_args!("process started"))
+                .build(),
+            span.fields,
+        )
+        .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_json() {
+        let line = line(LogFormat::Json);
+        assert!(line.ends_with('\n'));
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "ts": "2020-06-01T12:00:00Z",
+                "level": "INFO",
+                "target": TARGET,
+                "msg": "process started",
+                "span": "process",
+                "entry": "/bin/cat",
+                "batch": 2,
+                "pid": 42,
+            })
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(
+            line(LogFormat::Text),
+            "[2020-06-01T12:00:00Z INFO  ya_runtime_sgx] process started \
+             batch=2 entry=\"/bin/cat\" pid=42 span=\"process\"\n"
+        );
+    }
+}
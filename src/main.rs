@@ -1,42 +1,232 @@
 use futures::{
-    future::{BoxFuture, FutureExt},
+    channel::mpsc,
+    future::{self, BoxFuture, Either, FutureExt},
     lock::Mutex,
+    StreamExt,
 };
 use std::{
-    io::Read,
+    ffi::OsStr,
+    os::unix::process::CommandExt,
     path::{Path, PathBuf},
     process,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 use structopt::StructOpt;
-use tokio::{io::AsyncWriteExt, spawn};
+use tokio::{
+    io::AsyncWriteExt,
+    signal::unix::{signal, SignalKind},
+    spawn,
+};
 use ya_runtime_api::{deploy, server};
 
+mod checkpoint;
+mod cleanup;
+mod config;
+mod exec;
+mod logging;
+mod manifest;
+mod network;
+mod output;
+mod package;
+mod quota;
+mod service;
+mod stdin;
+
 #[derive(StructOpt)]
 enum Commands {
     Deploy {},
     Start {},
+    /// Graphene manifest tools
+    Manifest(ManifestCommand),
+    /// Archives workdir volumes, sealed if a sealing key is configured
+    Checkpoint {
+        /// Output file, which must not exist
+        #[structopt(short, long)]
+        output: PathBuf,
+        /// Workdir subdirectory to include
+        #[structopt(long = "volume", required = true)]
+        volumes: Vec<PathBuf>,
+    },
+    /// Restores a checkpoint into a fresh workdir, before start
+    Restore {
+        archive: PathBuf,
+    },
+}
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum ManifestCommand {
+    /// Generates a manifest from a JSON description
+    Generate {
+        description: PathBuf,
+        /// Output file, which must not exist, stdout if not given
+        #[structopt(short, long)]
+        output: Option<PathBuf>,
+        #[structopt(flatten)]
+        paths: ManifestPaths,
+    },
+    /// Compares an existing manifest with the one generated from a description
+    Diff {
+        description: PathBuf,
+        manifest: PathBuf,
+        #[structopt(flatten)]
+        paths: ManifestPaths,
+    },
+}
+
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+struct ManifestPaths {
+    /// Do not compute checksums of trusted files
+    #[structopt(long)]
+    no_checksums: bool,
+    /// Maps an enclave path prefix to a host directory, e.g. `/graphene=distro/graphene`
+    #[structopt(long = "map", parse(try_from_str = parse_path_mapping))]
+    mappings: Vec<(PathBuf, PathBuf)>,
+}
+
+fn parse_path_mapping(s: &str) -> Result<(PathBuf, PathBuf), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => {
+            Ok((from.into(), to.into()))
+        }
+        _ => Err(format!("expected <enclave path>=<host path>, got: {}", s)),
+    }
 }
 
 #[derive(StructOpt)]
 #[structopt(rename_all = "kebab-case")]
 struct CmdArgs {
     #[structopt(short, long)]
-    workdir: PathBuf,
+    workdir: Option<PathBuf>,
     #[structopt(short, long)]
-    task_package: PathBuf,
+    task_package: Option<PathBuf>,
+    #[structopt(flatten)]
+    options: RuntimeOptions,
     #[structopt(subcommand)]
     command: Commands,
 }
 
+/// Options overriding the configuration file.
+#[derive(StructOpt, Clone)]
+#[structopt(rename_all = "kebab-case")]
+struct RuntimeOptions {
+    /// Runtime configuration file (TOML)
+    #[structopt(long, env = "YA_RUNTIME_SGX_CONFIG")]
+    config: Option<PathBuf>,
+    /// Signed manifest whose trusted file checksums are verified before running a process.
+    /// Mimics enclave semantics when running without SGX.
+    #[structopt(long)]
+    verify_manifest: Option<PathBuf>,
+    /// Maximum size of files in the workdir, e.g. `512M`. Processes are killed when exceeded.
+    #[structopt(long, parse(try_from_str = quota::parse_size))]
+    workdir_quota: Option<u64>,
+    /// Interval between workdir usage scans, in seconds [default: 5]
+    #[structopt(long)]
+    quota_check_interval: Option<u64>,
+    /// What to do with the workdir after shutdown: keep, delete or wipe [default: keep]
+    #[structopt(long)]
+    cleanup: Option<cleanup::CleanupPolicy>,
+    /// Workdir subdirectory overwritten before removal with `--cleanup wipe`
+    #[structopt(long = "private-volume")]
+    private_volumes: Vec<PathBuf>,
+    /// Key sealing checkpoints, 32 bytes either raw or hex encoded
+    #[structopt(long)]
+    sealing_key_file: Option<PathBuf>,
+    /// Log format: text or json [default: text]
+    #[structopt(long)]
+    log_format: Option<logging::LogFormat>,
+}
+
+impl RuntimeOptions {
+    /// Loads the configuration file, if any, and applies options given on the command line.
+    fn load_config(&self) -> std::io::Result<config::Config> {
+        let mut config = match &self.config {
+            Some(path) => config::Config::from_file(path)?,
+            None => config::Config::default(),
+        };
+        if let Some(path) = &self.verify_manifest {
+            config.allowlist.verify_manifest = Some(path.clone());
+        }
+        if let Some(quota) = self.workdir_quota {
+            config.limits.workdir_quota = Some(quota);
+        }
+        if let Some(interval) = self.quota_check_interval {
+            config.limits.quota_check_interval = interval;
+        }
+        if let Some(policy) = self.cleanup {
+            config.cleanup.policy = policy;
+        }
+        if !self.private_volumes.is_empty() {
+            config.cleanup.private_volumes = self.private_volumes.clone();
+        }
+        if let Some(format) = self.log_format {
+            config.logging.format = format;
+        }
+        if let Some(path) = &self.sealing_key_file {
+            config.checkpoint.key_file = Some(path.clone());
+        }
+        config.validate().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid configuration: {}", e),
+            )
+        })?;
+        Ok(config)
+    }
+}
+
+fn required_arg<'a>(arg: &'a Option<PathBuf>, name: &str) -> std::io::Result<&'a PathBuf> {
+    arg.as_ref().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("missing required argument --{}", name),
+        )
+    })
+}
+
+/// How long the server is kept running after a shutdown on a signal.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
 struct Runtime {
     work_dir: PathBuf,
-    children: Arc<Mutex<Vec<process::Child>>>,
+    config: config::Config,
+    network: network::NetworkIsolation,
+    stdout: output::Sink,
+    stderr: output::Sink,
+    trusted_files: Option<Arc<manifest::TrustedFiles>>,
+    quota: Option<Arc<quota::WorkdirQuota>>,
+    service: Option<Arc<service::Supervisor>>,
+    /// Daemon declared by the task package.
+    service_entry: Option<package::ServiceEntry>,
+    children: Arc<Mutex<Vec<Child>>>,
+    /// Number of reaped children whose final status has not been emitted yet.
+    exiting: Arc<AtomicUsize>,
+    /// Process statuses to be emitted by the server.
+    events: mpsc::UnboundedSender<server::ProcessStatus>,
+    /// Last pid given to a built-in query, above the range of real pids.
+    builtin_pid: Arc<AtomicU64>,
+    /// Number of run requests received, logged as the `batch` of process spans.
+    batches: Arc<AtomicU64>,
+}
+
+struct Child {
+    inner: process::Child,
+    output: output::Capture,
+    span: logging::Span,
 }
 
-fn child_watcher<'a, E: server::RuntimeEvent + Send + Sync + 'static>(
-    event_emitter: E,
-    children: Arc<Mutex<Vec<process::Child>>>,
+fn child_watcher<'a>(
+    events: mpsc::UnboundedSender<server::ProcessStatus>,
+    children: Arc<Mutex<Vec<Child>>>,
+    exiting: Arc<AtomicUsize>,
+    quota: Option<Arc<quota::WorkdirQuota>>,
 ) -> BoxFuture<'a, ()> {
     async move {
         loop {
@@ -47,32 +237,35 @@ fn child_watcher<'a, E: server::RuntimeEvent + Send + Sync + 'static>(
             for (i, child) in children.iter_mut().enumerate() {
                 // TODO: expect("non-blocking wait for a child failed"), but this requires handling
                 // errors from the spawned process
-                if let Some(st) = child.try_wait().ok().flatten() {
+                if let Some(st) = child.inner.try_wait().ok().flatten() {
                     found = Some((i, st));
                     break;
                 }
             }
             let (child, st) = match found {
-                Some((i, st)) => (children.remove(i), st),
+                Some((i, st)) => {
+                    exiting.fetch_add(1, Ordering::SeqCst);
+                    (children.remove(i), st)
+                }
                 None => {
                     /* Drop the lock before sleeping. */
                     drop(children);
-                    tokio::time::delay_for(std::time::Duration::from_millis(100)).await;
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
                     continue;
                 }
             };
             drop(children);
 
-            let pid = child.id();
-            let mut out = Vec::new();
-            let mut err = Vec::new();
-            if let Some(mut stdout) = child.stdout {
-                let _ = stdout.read_to_end(&mut out);
-            }
-            if let Some(mut stderr) = child.stderr {
-                let _ = stderr.read_to_end(&mut err);
+            let pid = child.inner.id();
+            let (out, mut err) = child.output.finish().await;
+            if let Some(quota) = quota.as_ref().filter(|quota| quota.exceeded()) {
+                err.extend_from_slice(quota.describe().as_bytes());
             }
-            log::debug!("run process exit status: {:?}", st);
+            child
+                .span
+                .clone()
+                .with("return_code", st.code())
+                .log(log::Level::Info, format_args!("process finished: {}", st));
             let status = server::ProcessStatus {
                 pid: pid.into(),
                 running: false,
@@ -81,20 +274,172 @@ fn child_watcher<'a, E: server::RuntimeEvent + Send + Sync + 'static>(
                 stdout: out,
                 stderr: err,
             };
-            event_emitter.on_process_status(status);
+            let _ = events.unbounded_send(status);
+            exiting.fetch_sub(1, Ordering::SeqCst);
         }
     }
     .boxed()
 }
 
-async fn deploy<P: AsRef<Path>>(_task_package: P) -> std::io::Result<()> {
+/// Rescans the workdir on a blocking thread, returns `true` if the quota is exceeded.
+async fn scan_quota(quota: &Arc<quota::WorkdirQuota>) -> bool {
+    let scanned = Arc::clone(quota);
+    match tokio::task::spawn_blocking(move || scanned.scan()).await {
+        Ok(exceeded) => exceeded,
+        Err(e) => {
+            log::warn!("scanning workdir failed: {}", e);
+            true
+        }
+    }
+}
+
+fn quota_watcher<'a>(
+    quota: Arc<quota::WorkdirQuota>,
+    interval: Duration,
+    children: Arc<Mutex<Vec<Child>>>,
+    service: Option<Arc<service::Supervisor>>,
+) -> BoxFuture<'a, ()> {
+    async move {
+        loop {
+            tokio::time::delay_for(interval).await;
+            if !scan_quota(&quota).await {
+                log::trace!("workdir usage: {} bytes", quota.usage());
+                continue;
+            }
+            log::warn!("{}, killing all processes", quota.describe());
+            for child in children.lock().await.iter_mut() {
+                if let Err(e) = child.inner.kill() {
+                    child.span.log(
+                        log::Level::Warn,
+                        format_args!("killing process failed: {}", e),
+                    );
+                }
+            }
+            if let Some(service) = &service {
+                if let Err(e) = service.kill().await {
+                    log::warn!("killing service failed: {}", e);
+                }
+            }
+        }
+    }
+    .boxed()
+}
+
+fn build_manifest(
+    description: &Path,
+    paths: &ManifestPaths,
+) -> std::io::Result<manifest::Manifest> {
+    let spec = manifest::ManifestSpec::from_file(description)?;
+    let base = description.parent().unwrap_or_else(|| Path::new("."));
+    let mut resolver = manifest::PathResolver::new(base);
+    for (from, to) in &paths.mappings {
+        resolver.map(from, to);
+    }
+    spec.build(if paths.no_checksums {
+        None
+    } else {
+        Some(&resolver)
+    })
+}
+
+fn manifest_command(cmd: ManifestCommand) -> std::io::Result<()> {
+    match cmd {
+        ManifestCommand::Generate {
+            description,
+            output,
+            paths,
+        } => {
+            let m = build_manifest(&description, &paths)?;
+            match output {
+                Some(output) => write_new_file(&output, m.to_string().as_bytes())?,
+                None => print!("{}", m),
+            }
+        }
+        ManifestCommand::Diff {
+            description,
+            manifest,
+            paths,
+        } => {
+            let expected = build_manifest(&description, &paths)?;
+            let mut actual = manifest::Manifest::from_file(&manifest)?;
+            if paths.no_checksums {
+                actual = actual.without_checksums();
+            }
+            let diffs = expected.diff(&actual);
+            for diff in &diffs {
+                println!("{}", diff);
+            }
+            if !diffs.is_empty() {
+                process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn checkpoint_command(
+    cmd: Commands,
+    work_dir: &Path,
+    config: &config::Config,
+) -> std::io::Result<()> {
+    let key = match &config.checkpoint.key_file {
+        Some(path) => Some(checkpoint::SealingKey::from_file(path)?),
+        None => None,
+    };
+    match cmd {
+        Commands::Checkpoint { output, volumes } => {
+            if key.is_none() {
+                log::warn!("no sealing key configured, checkpoint is not encrypted");
+            }
+            let archive = checkpoint::checkpoint(work_dir, &volumes, key.as_ref())?;
+            write_new_file(&output, &archive)?;
+        }
+        Commands::Restore { archive } => {
+            let archive = std::fs::read(archive)?;
+            let allow_unsealed = config.checkpoint.allow_unsealed;
+            for volume in checkpoint::restore(work_dir, &archive, key.as_ref(), allow_unsealed)? {
+                log::info!("restored volume: {}", volume.display());
+            }
+        }
+        _ => unreachable!(),
+    }
+    Ok(())
+}
+
+/// Writes a file that must not exist yet. A symlink in its place, possibly planted by a
+/// workload, is not followed.
+fn write_new_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .and_then(|mut f| f.write_all(data))
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("writing {} failed: {}", path.display(), e),
+            )
+        })
+}
+
+async fn deploy(task_package: &Path) -> std::io::Result<()> {
+    let (valid, start_mode) = match package::Descriptor::from_package(task_package) {
+        Ok(Some(descriptor)) if descriptor.service.is_some() => {
+            (Ok(Default::default()), deploy::StartMode::Empty)
+        }
+        Ok(_) => (Ok(Default::default()), deploy::StartMode::Blocking),
+        Err(e) => (Err(e.to_string()), deploy::StartMode::Blocking),
+    };
     let res = deploy::DeployResult {
-        valid: Ok(Default::default()),
+        valid,
         vols: vec![deploy::ContainerVolume {
             name: ".".to_string(),
             path: "".to_string(),
         }],
-        start_mode: deploy::StartMode::Blocking,
+        start_mode,
     };
 
     let mut stdout = tokio::io::stdout();
@@ -107,13 +452,211 @@ async fn deploy<P: AsRef<Path>>(_task_package: P) -> std::io::Result<()> {
 }
 
 impl Runtime {
-    async fn new<E: server::RuntimeEvent + Send + Sync + 'static>(
+    async fn new(
         work_dir: PathBuf,
-        event_emitter: E,
+        task_package: Option<PathBuf>,
+        config: config::Config,
+        events: mpsc::UnboundedSender<server::ProcessStatus>,
     ) -> std::io::Result<Self> {
+        let network = config.network_isolation(task_package.as_deref());
+        log::debug!("network isolation: {:?}", network);
+        let trusted_files = match &config.allowlist.verify_manifest {
+            Some(path) => Some(Arc::new(manifest::TrustedFiles::from_manifest(path)?)),
+            None => None,
+        };
         let children = Arc::new(Mutex::new(Vec::new()));
-        spawn(child_watcher(event_emitter, Arc::clone(&children)));
-        Ok(Self { work_dir, children })
+        let service_entry = match &task_package {
+            Some(path) => package::Descriptor::from_package(path)?.and_then(|d| d.service),
+            None => None,
+        };
+        let service = service_entry.as_ref().map(|entry| {
+            Arc::new(service::Supervisor::new(
+                &entry.entry_point,
+                config.service.restart,
+                config.service.backoff(),
+            ))
+        });
+        let quota = config
+            .limits
+            .workdir_quota
+            .map(|limit| Arc::new(quota::WorkdirQuota::new(&work_dir, limit)));
+        if let Some(quota) = &quota {
+            scan_quota(quota).await;
+            spawn(quota_watcher(
+                Arc::clone(quota),
+                config.quota_check_interval(),
+                Arc::clone(&children),
+                service.clone(),
+            ));
+        }
+        let exiting = Arc::new(AtomicUsize::new(0));
+        spawn(child_watcher(
+            events.clone(),
+            Arc::clone(&children),
+            Arc::clone(&exiting),
+            quota.clone(),
+        ));
+        let sink = |name: &str| output::Sink {
+            buffer_size: if config.output.capture {
+                config.output.buffer_size as usize
+            } else {
+                0
+            },
+            log: if config.output.log_files {
+                Some(Arc::new(std::sync::Mutex::new(output::RotatingFile::new(
+                    work_dir.join(name),
+                    config.output.log_file_size,
+                    config.output.log_file_count,
+                ))))
+            } else {
+                None
+            },
+        };
+        let stdout = sink("stdout.log");
+        let stderr = sink("stderr.log");
+        let runtime = Self {
+            work_dir,
+            config,
+            network,
+            stdout,
+            stderr,
+            trusted_files,
+            quota,
+            service: service.clone(),
+            service_entry,
+            children,
+            exiting,
+            events,
+            builtin_pid: Arc::new(AtomicU64::new(u32::MAX.into())),
+            batches: Arc::new(AtomicU64::new(0)),
+        };
+        Ok(runtime)
+    }
+
+    /// Starts the daemon declared by the task package, if any, under supervision.
+    fn start(&self) {
+        if let (Some(service), Some(entry)) = (self.service.clone(), self.service_entry.clone()) {
+            let runtime = self.clone();
+            spawn(async move { service.run(|| runtime.start_service(&entry)).await });
+        }
+    }
+
+    fn start_service(
+        &self,
+        entry: &package::ServiceEntry,
+    ) -> Result<(process::Child, output::Capture), String> {
+        let exe = self.check_executable(&entry.entry_point)?;
+        if let Some(quota) = self.quota.as_ref().filter(|quota| quota.exceeded()) {
+            return Err(quota.describe());
+        }
+        let mut child = self
+            .command(self.program(&exe))?
+            .arg0(&entry.entry_point)
+            .args(&entry.args)
+            .stdin(process::Stdio::null())
+            .spawn()
+            .map_err(|e| format!("running process failed: {}", e))?;
+        let output = output::Capture::start(&mut child, &self.stdout, &self.stderr);
+        Ok((child, output))
+    }
+
+    /// Resolves `bin` and checks it against the allowlist and the trusted files of the manifest.
+    /// The returned executable is the one to run.
+    fn check_executable(&self, bin: &str) -> Result<exec::Executable, String> {
+        let path = exec::resolve(bin, &self.work_dir, self.config.search_path().as_deref())
+            .map_err(|e| format!("executable not found: {}", e))?;
+        let exe = exec::Executable::open(&path)
+            .map_err(|e| format!("opening {} failed: {}", path.display(), e))?;
+        if !self.config.is_allowed(&exe.path) {
+            return Err(format!("executable not allowed: {}", exe.path.display()));
+        }
+        if let Some(trusted_files) = &self.trusted_files {
+            trusted_files
+                .verify(&exe)
+                .map_err(|e| format!("untrusted executable: {}", e))?;
+        }
+        Ok(exe)
+    }
+
+    /// Program running `exe`. Verified executables are run through the open descriptor, which
+    /// cannot run `#!` scripts; others by their resolved path.
+    fn program(&self, exe: &exec::Executable) -> PathBuf {
+        if self.trusted_files.is_some() {
+            exe.program()
+        } else {
+            exe.path.clone()
+        }
+    }
+
+    /// Prepares a process with the configured environment, network isolation and output.
+    fn command<S: AsRef<OsStr>>(&self, bin: S) -> Result<process::Command, String> {
+        let output = || {
+            if self.config.output.capture || self.config.output.log_files {
+                process::Stdio::piped()
+            } else {
+                process::Stdio::null()
+            }
+        };
+        let mut command = process::Command::new(bin);
+        if self.config.env.clear {
+            command.env_clear();
+        }
+        network::isolate(&mut command, self.network)
+            .map_err(|e| format!("network isolation failed: {}", e))?;
+        command
+            .envs(&self.config.env.vars)
+            .stdout(output())
+            .stderr(output())
+            .current_dir(&self.work_dir);
+        Ok(command)
+    }
+
+    /// Answers the usage query with a process whose stdout is the usage report. As for spawned
+    /// processes, its status is emitted once the scan it runs completes.
+    fn report_usage(&self) -> server::RunProcessResp {
+        let pid = self.builtin_pid.fetch_add(1, Ordering::SeqCst) + 1;
+        let quota = self.quota.clone();
+        let work_dir = self.work_dir.clone();
+        let events = self.events.clone();
+        spawn(async move {
+            let report = match quota {
+                Some(quota) => {
+                    let exceeded = scan_quota(&quota).await;
+                    Ok(quota::report(quota.usage(), Some(quota.limit()), exceeded))
+                }
+                None => tokio::task::spawn_blocking(move || quota::dir_usage(work_dir))
+                    .await
+                    .map(|usage| quota::report(usage.bytes, None, usage.errors > 0)),
+            };
+            let (return_code, stdout, stderr) = match report {
+                Ok(report) => (0, report.into_bytes(), Vec::new()),
+                Err(e) => (
+                    1,
+                    Vec::new(),
+                    format!("scanning workdir failed: {}", e).into_bytes(),
+                ),
+            };
+            let _ = events.unbounded_send(server::ProcessStatus {
+                pid,
+                running: false,
+                return_code,
+                stdout,
+                stderr,
+            });
+        });
+        server::RunProcessResp { pid }
+    }
+
+    /// Waits until all children are reaped and their final statuses are emitted.
+    async fn wait_for_children(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !self.children.lock().await.is_empty() || self.exiting.load(Ordering::SeqCst) > 0 {
+            if Instant::now() > deadline {
+                return false;
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        true
     }
 }
 
@@ -128,28 +671,70 @@ impl server::RuntimeService for Runtime {
         run: server::RunProcess,
     ) -> server::AsyncResponse<'_, server::RunProcessResp> {
         log::debug!("run process: {:?}", run);
+        let batch = self.batches.fetch_add(1, Ordering::Relaxed) + 1;
         async move {
-            let mut command = process::Command::new(run.bin);
-            /* Uncomment once this (arg0) feature is stable.
-            if run.args.len() > 0 {
-                command.arg0(run.args[0]);
-            }
-            */
-            if run.args.len() > 1 {
-                command.args(&run.args[1..]);
-            }
-            let child = command
-                .stdin(std::process::Stdio::null())
-                .stdout(std::process::Stdio::null())
-                .stderr(std::process::Stdio::null())
-                .current_dir(&self.work_dir)
+            if run.bin == quota::COMMAND {
+                return Ok(self.report_usage());
+            }
+            let (bin, args) = if run.bin == checkpoint::COMMAND {
+                checkpoint::self_invocation(
+                    &self.work_dir,
+                    self.config.checkpoint.key_file.as_deref(),
+                    run.args,
+                )
+                .map_err(|e| {
+                    server::ErrorResponse::msg(format!("{}: {}", checkpoint::COMMAND, e))
+                })?
+            } else {
+                (run.bin, run.args)
+            };
+            let invocation =
+                stdin::Invocation::parse(bin, args).map_err(server::ErrorResponse::msg)?;
+            /* Kept open until the process is spawned. The runtime itself, run by a built-in,
+             * is checked like any other executable. */
+            let exe = self
+                .check_executable(&invocation.bin)
+                .map_err(server::ErrorResponse::msg)?;
+            let program = self.program(&exe);
+            if let Some(quota) = self.quota.as_ref().filter(|quota| quota.exceeded()) {
+                return Err(server::ErrorResponse::msg(quota.describe()));
+            }
+            let mut children = self.children.lock().await;
+            if let Some(max) = self.config.limits.max_processes {
+                if children.len() >= max {
+                    return Err(server::ErrorResponse::msg(format!(
+                        "too many running processes (limit: {})",
+                        max
+                    )));
+                }
+            }
+            let stdin = invocation
+                .stdin
+                .stdio(&self.work_dir)
+                .map_err(|e| server::ErrorResponse::msg(format!("opening stdin failed: {}", e)))?;
+            let span = logging::Span::new("process")
+                .with("entry", invocation.bin.as_str())
+                .with("batch", batch);
+            let mut child = self
+                .command(program)
+                .map_err(server::ErrorResponse::msg)?
+                .arg0(&invocation.bin)
+                .args(invocation.args)
+                .stdin(stdin)
                 .spawn()
                 .map_err(|e| {
                     server::ErrorResponse::msg(format!("running process failed: {}", e))
                 })?;
+            invocation.stdin.feed(&mut child);
             let pid = child.id();
-            let mut children = self.children.lock().await;
-            children.push(child);
+            let span = span.with("pid", pid);
+            span.log(log::Level::Info, format_args!("process started"));
+            let output = output::Capture::start(&mut child, &self.stdout, &self.stderr);
+            children.push(Child {
+                inner: child,
+                output,
+                span,
+            });
             Ok(server::RunProcessResp { pid: pid.into() })
         }
         .boxed_local()
@@ -161,14 +746,19 @@ impl server::RuntimeService for Runtime {
             let mut children = self.children.lock().await;
             match children
                 .iter_mut()
-                .find(|child| child.id() as u64 == kill.pid)
+                .find(|child| child.inner.id() as u64 == kill.pid)
             {
-                Some(child) => child.kill().map_err(|e| {
-                    server::ErrorResponse::msg(format!(
-                        "killing process (pid: {}) failed: {}",
-                        kill.pid, e
-                    ))
-                }),
+                Some(child) => {
+                    child
+                        .span
+                        .log(log::Level::Debug, format_args!("killing process"));
+                    child.inner.kill().map_err(|e| {
+                        server::ErrorResponse::msg(format!(
+                            "killing process (pid: {}) failed: {}",
+                            kill.pid, e
+                        ))
+                    })
+                }
                 None => Err(server::ErrorResponse::msg(format!(
                     "no such process (pid: {}) to kill",
                     kill.pid
@@ -181,23 +771,43 @@ impl server::RuntimeService for Runtime {
     fn shutdown(&self) -> server::AsyncResponse<'_, ()> {
         log::debug!("shutdown");
         async move {
-            let mut children = self.children.lock().await;
             let mut fails = Vec::new();
+            if let Some(service) = &self.service {
+                if let Err(e) = service.stop(self.config.shutdown_timeout()).await {
+                    fails.push(format!("stopping service failed: {}", e));
+                }
+            }
+            let mut children = self.children.lock().await;
             for child in children.iter_mut() {
-                if let Err(_) = child.kill() {
-                    fails.push(child.id());
+                if child.inner.kill().is_err() {
+                    fails.push(format!(
+                        "killing process (pid: {}) failed",
+                        child.inner.id()
+                    ));
                 }
             }
-            // TODO: kill child_watcher, perhaps giving it some time to catch up with all the children
-            // being killed
-            if fails.len() > 0 {
-                Err(server::ErrorResponse::msg(format!(
-                    "failed to kill children: {:?}",
-                    fails
-                )))
-            } else {
-                Ok(())
+            drop(children);
+            // TODO: kill child_watcher
+            /* Give child_watcher some time to catch up with all the children being killed. */
+            let reaped = self.wait_for_children(self.config.shutdown_timeout()).await;
+            let policy = self.config.cleanup.policy;
+            if !reaped {
+                fails.push("children not reaped, workdir cleanup skipped".to_string());
+            } else if policy != cleanup::CleanupPolicy::Keep {
+                log::debug!("workdir cleanup: {:?}", policy);
+                if let Err(e) =
+                    cleanup::cleanup(&self.work_dir, policy, &self.config.cleanup.private_volumes)
+                {
+                    fails.push(format!("workdir cleanup failed: {}", e));
+                }
+            }
+            if !fails.is_empty() {
+                return Err(server::ErrorResponse::msg(format!(
+                    "shutdown failed: {}",
+                    fails.join(", ")
+                )));
             }
+            Ok(())
         }
         .boxed_local()
     }
@@ -205,18 +815,218 @@ impl server::RuntimeService for Runtime {
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
     let cmdargs = CmdArgs::from_args();
+    let config = match cmdargs.command {
+        Commands::Manifest(_) => config::Config::default(),
+        _ => cmdargs.options.load_config().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(2)
+        }),
+    };
+    logging::init(&config.logging.level, config.logging.format);
     match cmdargs.command {
-        Commands::Deploy {} => deploy(&cmdargs.task_package).await?,
+        Commands::Deploy {} => deploy(required_arg(&cmdargs.task_package, "task-package")?).await?,
         Commands::Start {} => {
-            server::run_async(|e| async {
-                Runtime::new(cmdargs.workdir.clone(), e)
-                    .await
-                    .expect("failed to start runtime")
-            })
-            .await
+            let workdir = required_arg(&cmdargs.workdir, "workdir")?.clone();
+            let task_package = cmdargs.task_package.clone();
+            /* Register handlers first, so that early signals are not lost. */
+            let mut terminate = signal(SignalKind::terminate())?;
+            let mut interrupt = signal(SignalKind::interrupt())?;
+            let (events, mut statuses) = mpsc::unbounded();
+            let runtime = Runtime::new(workdir, task_package, config, events).await?;
+            runtime.start();
+            let server = server::run_async(|e| {
+                let runtime = runtime.clone();
+                async move {
+                    spawn(async move {
+                        while let Some(status) = statuses.next().await {
+                            server::RuntimeEvent::on_process_status(&e, status);
+                        }
+                    });
+                    runtime
+                }
+            });
+            let signals = async {
+                future::select(terminate.recv().boxed(), interrupt.recv().boxed()).await;
+                log::info!("termination signal received, shutting down");
+                if let Err(e) = server::RuntimeService::shutdown(&runtime).await {
+                    log::error!("shutdown failed: {:?}", e);
+                }
+            };
+            let done = future::select(server.boxed_local(), signals.boxed_local()).await;
+            if let Either::Right((_, server)) = done {
+                /* Keep serving for a while, so that the final statuses are sent. */
+                let _ = tokio::time::timeout(FLUSH_TIMEOUT, server).await;
+            }
         }
+        Commands::Manifest(cmd) => manifest_command(cmd)?,
+        cmd => checkpoint_command(cmd, required_arg(&cmdargs.workdir, "workdir")?, &config)?,
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_service() -> std::io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("runtime-service-{}", std::process::id()));
+        let work_dir = dir.join("work");
+        fs::create_dir_all(&work_dir)?;
+        let task_package = dir.join("service.ywasi");
+        package::test::write_package(
+            &task_package,
+            r#"{"service": {"entry-point": "/bin/sleep", "args": ["100"]}}"#,
+        )?;
+        let mut config = config::Config::default();
+        config.output.capture = true;
+        let (events, mut statuses) = mpsc::unbounded();
+        let runtime = Runtime::new(work_dir, Some(task_package), config, events).await?;
+        let service = runtime.service.clone().unwrap();
+        /* Not started before the `start` command. */
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert!(service.pid().await.is_none());
+
+        runtime.start();
+        let mut attempts = 0;
+        while service.pid().await.is_none() {
+            attempts += 1;
+            assert!(attempts < 50, "service not started");
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        let run = server::RunProcess {
+            bin: "/bin/echo".into(),
+            args: vec!["echo".into(), "hello".into()],
+            ..Default::default()
+        };
+        let pid = server::RuntimeService::run_process(&runtime, run)
+            .await
+            .unwrap()
+            .pid;
+        let status = tokio::time::timeout(Duration::from_secs(5), statuses.next())
+            .await?
+            .unwrap();
+        assert_eq!((status.pid, status.return_code), (pid, 0));
+        assert_eq!(status.stdout, b"hello\n");
+
+        server::RuntimeService::shutdown(&runtime).await.unwrap();
+        assert!(service.pid().await.is_none());
+
+        fs::remove_dir_all(&dir)
+    }
+
+    #[tokio::test]
+    async fn test_usage() -> std::io::Result<()> {
+        let work_dir = std::env::temp_dir().join(format!("runtime-usage-{}", std::process::id()));
+        fs::create_dir_all(&work_dir)?;
+        fs::write(work_dir.join("data"), vec![0u8; 100])?;
+        let mut config = config::Config::default();
+        config.limits.workdir_quota = Some(1024);
+        let (events, mut statuses) = mpsc::unbounded();
+        let runtime = Runtime::new(work_dir.clone(), None, config, events).await?;
+
+        let run = server::RunProcess {
+            bin: quota::COMMAND.into(),
+            ..Default::default()
+        };
+        let pid = server::RuntimeService::run_process(&runtime, run)
+            .await
+            .unwrap()
+            .pid;
+        assert!(pid > u32::MAX.into());
+        let status = tokio::time::timeout(Duration::from_secs(5), statuses.next())
+            .await?
+            .unwrap();
+        assert_eq!((status.pid, status.return_code), (pid, 0));
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&status.stdout)?,
+            serde_json::json!({"usage": 100, "limit": 1024, "exceeded": false})
+        );
+
+        fs::remove_dir_all(&work_dir)
+    }
+
+    #[tokio::test]
+    async fn test_script() -> std::io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let work_dir = std::env::temp_dir().join(format!("runtime-script-{}", std::process::id()));
+        fs::create_dir_all(&work_dir)?;
+        let script = work_dir.join("hello.sh");
+        fs::write(&script, "#!/bin/sh\necho hello from $0\n")?;
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+        let mut config = config::Config::default();
+        config.output.capture = true;
+        let (events, mut statuses) = mpsc::unbounded();
+        let runtime = Runtime::new(work_dir.clone(), None, config, events).await?;
+
+        /* Without trusted-file verification, scripts run by their path. */
+        let run = server::RunProcess {
+            bin: "./hello.sh".into(),
+            args: vec!["hello.sh".into()],
+            ..Default::default()
+        };
+        let pid = server::RuntimeService::run_process(&runtime, run)
+            .await
+            .unwrap()
+            .pid;
+        let status = tokio::time::timeout(Duration::from_secs(5), statuses.next())
+            .await?
+            .unwrap();
+        assert_eq!((status.pid, status.return_code), (pid, 0));
+        assert_eq!(
+            String::from_utf8_lossy(&status.stdout),
+            format!("hello from {}\n", script.canonicalize()?.display())
+        );
+
+        server::RuntimeService::shutdown(&runtime).await.unwrap();
+        fs::remove_dir_all(&work_dir)
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_allowlist() -> std::io::Result<()> {
+        let work_dir =
+            std::env::temp_dir().join(format!("runtime-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(work_dir.join("private"))?;
+        let mut config = config::Config::default();
+        config.allowlist.binaries = vec!["/bin/echo".into()];
+        let (events, _statuses) = mpsc::unbounded();
+        let runtime = Runtime::new(work_dir.clone(), None, config, events).await?;
+
+        /* The runtime binary is not allowed, so neither is the built-in running it. */
+        let run = server::RunProcess {
+            bin: checkpoint::COMMAND.into(),
+            args: vec![
+                checkpoint::COMMAND.into(),
+                "state.ckpt".into(),
+                "private".into(),
+            ],
+            ..Default::default()
+        };
+        let err = server::RuntimeService::run_process(&runtime, run)
+            .await
+            .unwrap_err();
+        assert!(err.message.contains("executable not allowed"), "{:?}", err);
+        assert!(!work_dir.join("state.ckpt").exists());
+
+        fs::remove_dir_all(&work_dir)
+    }
+
+    #[test]
+    fn test_write_new_file() -> std::io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("runtime-write-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("target"), "target")?;
+        std::os::unix::fs::symlink(dir.join("target"), dir.join("link"))?;
+
+        write_new_file(&dir.join("new"), b"new")?;
+        assert_eq!(fs::read(dir.join("new"))?, b"new");
+        assert!(write_new_file(&dir.join("new"), b"again").is_err());
+        assert!(write_new_file(&dir.join("link"), b"overwritten").is_err());
+        assert_eq!(fs::read(dir.join("target"))?, b"target");
+
+        fs::remove_dir_all(&dir)
+    }
+}
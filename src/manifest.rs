@@ -0,0 +1,486 @@
+//! Graphene manifest generation from a declarative description.
+//!
+//! The description is a JSON document listing the entry point, mounts, trusted and allowed
+//! files, environment and enclave parameters. [`ManifestSpec::build`] turns it into a
+//! [`Manifest`], hashing every trusted file on the way, which can be rendered in the format
+//! expected by `pal-sgx-sign` or compared against an existing, hand-maintained manifest.
+use crate::exec::Executable;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+const FILE_URI_PREFIX: &str = "file:";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ManifestSpec {
+    /// Path of the executable started inside the enclave.
+    pub exec: String,
+    #[serde(default = "default_preload")]
+    pub preload: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub mounts: BTreeMap<String, MountSpec>,
+    /// Files measured into the enclave, by name.
+    #[serde(default)]
+    pub trusted_files: BTreeMap<String, String>,
+    /// Signatures of child enclaves allowed to be spawned, by name.
+    #[serde(default)]
+    pub trusted_children: BTreeMap<String, String>,
+    /// Files accessible without integrity checks, by name.
+    #[serde(default)]
+    pub allowed_files: BTreeMap<String, String>,
+    #[serde(default)]
+    pub allow_file_creation: bool,
+    pub thread_num: Option<u32>,
+    pub enclave_size: Option<String>,
+    /// Verbatim `key = value` entries not covered by the fields above.
+    #[serde(default)]
+    pub extra: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MountSpec {
+    #[serde(default = "default_mount_type")]
+    #[serde(rename = "type")]
+    pub mount_type: String,
+    pub path: String,
+    pub uri: String,
+}
+
+fn default_preload() -> Option<String> {
+    Some("/graphene/Runtime/libsysdb.so".to_string())
+}
+
+fn default_mount_type() -> String {
+    "chroot".to_string()
+}
+
+/// Maps paths as seen inside the enclave to paths on the machine generating the manifest.
+///
+/// Absolute paths are resolved via the longest matching prefix, or left unchanged.
+/// Relative paths are resolved against `base`.
+pub struct PathResolver {
+    base: PathBuf,
+    prefixes: Vec<(PathBuf, PathBuf)>,
+}
+
+impl PathResolver {
+    pub fn new<P: Into<PathBuf>>(base: P) -> Self {
+        Self {
+            base: base.into(),
+            prefixes: Vec::new(),
+        }
+    }
+
+    pub fn map<P: Into<PathBuf>, Q: Into<PathBuf>>(&mut self, from: P, to: Q) {
+        self.prefixes.push((from.into(), to.into()));
+        self.prefixes
+            .sort_by_key(|(from, _)| std::cmp::Reverse(from.components().count()));
+    }
+
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+        if path.is_relative() {
+            return self.base.join(path);
+        }
+        for (from, to) in &self.prefixes {
+            if let Ok(rest) = path.strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+        path.to_path_buf()
+    }
+}
+
+impl ManifestSpec {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let f = fs::File::open(path)?;
+        serde_json::from_reader(io::BufReader::new(f)).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid manifest description {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Builds the manifest. Checksums of trusted files are computed only if `resolver` is given.
+    pub fn build(&self, resolver: Option<&PathResolver>) -> io::Result<Manifest> {
+        let mut m = Manifest::default();
+
+        m.push("loader.exec", file_uri(&self.exec));
+        if let Some(preload) = &self.preload {
+            m.push("loader.preload", file_uri(preload));
+        }
+        for (k, v) in &self.env {
+            m.push(format!("loader.env.{}", k), v);
+        }
+        m.push("loader.debug_type", "none");
+        m.push("loader.syscall_symbol", "syscalldb");
+        m.push("loader.insecure__use_cmdline_argv", "1");
+
+        for (name, mount) in &self.mounts {
+            m.push(format!("fs.mount.{}.type", name), &mount.mount_type);
+            m.push(format!("fs.mount.{}.path", name), &mount.path);
+            m.push(format!("fs.mount.{}.uri", name), file_uri(&mount.uri));
+        }
+
+        for (name, path) in &self.trusted_files {
+            m.push(format!("sgx.trusted_files.{}", name), file_uri(path));
+            if let Some(resolver) = resolver {
+                let host_path = resolver.resolve(path);
+                let checksum = sha256_file(&host_path).map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("hashing trusted file {} failed: {}", host_path.display(), e),
+                    )
+                })?;
+                m.push(format!("sgx.trusted_checksum.{}", name), checksum);
+            }
+        }
+        for (name, path) in &self.trusted_children {
+            m.push(format!("sgx.trusted_children.{}", name), file_uri(path));
+        }
+        for (name, path) in &self.allowed_files {
+            m.push(format!("sgx.allowed_files.{}", name), file_uri(path));
+        }
+
+        if self.allow_file_creation {
+            m.push("sgx.allow_file_creation", "1");
+        }
+        if let Some(size) = &self.enclave_size {
+            m.push("sgx.enclave_size", size);
+        }
+        if let Some(n) = self.thread_num {
+            m.push("sgx.thread_num", n.to_string());
+        }
+        for (k, v) in &self.extra {
+            m.push(k, v);
+        }
+        Ok(m)
+    }
+}
+
+fn file_uri(path: &str) -> String {
+    if path.starts_with(FILE_URI_PREFIX) {
+        path.to_string()
+    } else {
+        format!("{}{}", FILE_URI_PREFIX, path)
+    }
+}
+
+/// Hex-encoded SHA-256 of the file contents.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    sha256_read(fs::File::open(path)?)
+}
+
+fn sha256_read<R: Read>(mut f: R) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.input(&buf[..n]);
+    }
+    Ok(hex::encode(hasher.result()))
+}
+
+/// Ordered list of manifest entries.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    entries: Vec<(String, String)>,
+}
+
+impl Manifest {
+    pub fn push<K: Into<String>, V: Into<String>>(&mut self, key: K, value: V) {
+        let key = key.into();
+        let value = value.into();
+        match self.entries.iter_mut().find(|(k, _)| *k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key, value)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Drops checksums of trusted files, to compare against a manifest built without them.
+    pub fn without_checksums(mut self) -> Self {
+        self.entries
+            .retain(|(k, _)| !k.starts_with("sgx.trusted_checksum."));
+        self
+    }
+
+    /// Parses a manifest in the `key = value` format, skipping comments and empty lines.
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut m = Manifest::default();
+        for (no, line) in content.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(idx) => &line[..idx],
+                None => line,
+            }
+            .trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if !k.trim().is_empty() => m.push(k.trim(), v.trim()),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid manifest entry at line {}: {:?}", no + 1, line),
+                    ))
+                }
+            }
+        }
+        Ok(m)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Lists differences between `self` (the expected manifest) and `other`.
+    pub fn diff<'a>(&'a self, other: &'a Manifest) -> Vec<Difference<'a>> {
+        let mut diffs = Vec::new();
+        for (k, v) in self.entries() {
+            match other.get(k) {
+                None => diffs.push(Difference::Missing(k, v)),
+                Some(o) if o != v => diffs.push(Difference::Changed(k, o, v)),
+                Some(_) => (),
+            }
+        }
+        for (k, v) in other.entries() {
+            if self.get(k).is_none() {
+                diffs.push(Difference::Unexpected(k, v));
+            }
+        }
+        diffs
+    }
+}
+
+/// Checksums of `sgx.trusted_files`, used to mimic enclave loading semantics outside SGX.
+pub struct TrustedFiles {
+    files: HashMap<PathBuf, Option<String>>,
+}
+
+impl TrustedFiles {
+    /// Collects trusted files from a signed manifest. Relative paths are resolved against the
+    /// manifest directory, as done by `pal-sgx-sign`.
+    pub fn from_manifest<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let manifest = Manifest::from_file(path)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+        let files = manifest
+            .entries()
+            .filter_map(|(k, v)| {
+                let name = k.strip_prefix("sgx.trusted_files.")?;
+                let file = base.join(v.strip_prefix(FILE_URI_PREFIX).unwrap_or(v));
+                let checksum = manifest
+                    .get(&format!("sgx.trusted_checksum.{}", name))
+                    .map(str::to_string);
+                Some((canonical(&file), checksum))
+            })
+            .collect();
+        Ok(Self { files })
+    }
+
+    /// Checks that `exe` is trusted and the contents of the opened file match the declared
+    /// checksum.
+    pub fn verify(&self, exe: &Executable) -> Result<(), String> {
+        let file = &exe.path;
+        let expected = match self.files.get(file) {
+            Some(Some(checksum)) => checksum,
+            Some(None) => return Err(format!("no checksum for {}", file.display())),
+            None => return Err(format!("{} is not a trusted file", file.display())),
+        };
+        let actual = sha256_read(&exe.file)
+            .map_err(|e| format!("hashing {} failed: {}", file.display(), e))?;
+        if actual.eq_ignore_ascii_case(expected) {
+            Ok(())
+        } else {
+            Err(format!(
+                "checksum mismatch for {}: expected {}, got {}",
+                file.display(),
+                expected,
+                actual
+            ))
+        }
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut prev_section: Option<&str> = None;
+        for (k, v) in self.entries() {
+            let section = section_of(k);
+            if prev_section.map(|p| p != section).unwrap_or(false) {
+                writeln!(f)?;
+            }
+            prev_section = Some(section);
+            writeln!(f, "{} = {}", k, v)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups entries for rendering, blank lines are put between the groups.
+fn section_of(key: &str) -> &str {
+    const SECTIONS: &[&str] = &["loader.env.", "sgx.trusted_", "sgx.allowed_files."];
+    if let Some(section) = SECTIONS.iter().find(|s| key.starts_with(*s)) {
+        return section;
+    }
+    let depth = if key.starts_with("fs.mount.") { 3 } else { 1 };
+    match key.match_indices('.').nth(depth - 1) {
+        Some((idx, _)) => &key[..idx],
+        None => key,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Difference<'a> {
+    /// Entry expected by the description, but absent from the manifest.
+    Missing(&'a str, &'a str),
+    /// Entry present in the manifest, but not produced by the description.
+    Unexpected(&'a str, &'a str),
+    /// Entry with a different value: key, actual, expected.
+    Changed(&'a str, &'a str, &'a str),
+}
+
+impl fmt::Display for Difference<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Missing(k, v) => write!(f, "+ {} = {}", k, v),
+            Difference::Unexpected(k, v) => write!(f, "- {} = {}", k, v),
+            Difference::Changed(k, actual, expected) => {
+                write!(f, "- {} = {}\n+ {} = {}", k, actual, k, expected)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SPEC: &str = r#"{
+        "exec": "/work/ya-runtime-sgx",
+        "env": { "RUST_LOG": "debug" },
+        "mounts": {
+            "graphene_lib": { "path": "/lib", "uri": "/graphene/Runtime" }
+        },
+        "trusted-files": { "hello": "hello" },
+        "allowed-files": { "in": "file_in" },
+        "allow-file-creation": true,
+        "thread-num": 16
+    }"#;
+
+    #[test]
+    fn test_build_and_parse() -> io::Result<()> {
+        let spec: ManifestSpec = serde_json::from_str(SPEC)?;
+        let m = spec.build(None)?;
+        assert_eq!(m.get("loader.exec"), Some("file:/work/ya-runtime-sgx"));
+        assert_eq!(m.get("fs.mount.graphene_lib.type"), Some("chroot"));
+        assert_eq!(m.get("sgx.trusted_files.hello"), Some("file:hello"));
+        assert_eq!(m.get("sgx.thread_num"), Some("16"));
+        assert_eq!(Manifest::parse(&m.to_string())?, m);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff() -> io::Result<()> {
+        let spec: ManifestSpec = serde_json::from_str(SPEC)?;
+        let expected = spec.build(None)?;
+        let mut actual = Manifest::parse(&expected.to_string())?;
+        actual.push("sgx.thread_num", "4");
+        actual.push("sgx.enclave_size", "8G");
+        let actual = Manifest::parse(
+            &format!("{}\n# comment\n", actual).replace("loader.env.RUST_LOG = debug\n", ""),
+        )?;
+
+        assert_eq!(
+            expected.diff(&actual),
+            vec![
+                Difference::Missing("loader.env.RUST_LOG", "debug"),
+                Difference::Changed("sgx.thread_num", "4", "16"),
+                Difference::Unexpected("sgx.enclave_size", "8G"),
+            ]
+        );
+        assert!(expected.diff(&expected).is_empty());
+
+        /* A signed manifest compared with `--no-checksums`. */
+        let mut signed = expected.clone();
+        signed.push("sgx.trusted_checksum.hello", "00");
+        assert_eq!(
+            expected.diff(&signed),
+            vec![Difference::Unexpected("sgx.trusted_checksum.hello", "00")]
+        );
+        assert!(expected.diff(&signed.without_checksums()).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolver() {
+        let mut r = PathResolver::new("/base");
+        r.map("/graphene", "/tmp/graphene");
+        r.map("/graphene/Runtime", "/opt/runtime");
+        assert_eq!(r.resolve("hello"), Path::new("/base/hello"));
+        assert_eq!(r.resolve("/graphene/x"), Path::new("/tmp/graphene/x"));
+        assert_eq!(
+            r.resolve("/graphene/Runtime/libc.so.6"),
+            Path::new("/opt/runtime/libc.so.6")
+        );
+        assert_eq!(r.resolve("/lib/libc.so.6"), Path::new("/lib/libc.so.6"));
+    }
+
+    #[test]
+    fn test_trusted_files() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("trusted-files-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("hello"), b"hello")?;
+        fs::write(dir.join("other"), b"other")?;
+        fs::write(
+            dir.join("hello.manifest.sgx"),
+            format!(
+                "sgx.trusted_files.hello = file:hello\nsgx.trusted_checksum.hello = {}\n",
+                sha256_file(dir.join("hello"))?
+            ),
+        )?;
+
+        assert_eq!(
+            sha256_file(dir.join("hello"))?,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        let trusted = TrustedFiles::from_manifest(dir.join("hello.manifest.sgx"))?;
+        let verify = |name: &str| trusted.verify(&Executable::open(&dir.join(name)).unwrap());
+        assert_eq!(verify("hello"), Ok(()));
+        assert!(verify("other").is_err());
+        fs::write(dir.join("hello"), b"tampered")?;
+        assert!(verify("hello").is_err());
+
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,316 @@
+//! Network isolation of spawned processes.
+//!
+//! Both mechanisms are applied in the child between `fork` and `exec`, so only async-signal-safe
+//! calls are made there. Neither is available under Graphene; spawning an isolated process fails
+//! there instead of silently running it with network access.
+use serde::Deserialize;
+use std::{
+    ffi::CString,
+    io,
+    os::{
+        raw::{c_char, c_int, c_short, c_ulong, c_ushort},
+        unix::process::CommandExt,
+    },
+    process,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkIsolation {
+    /// Processes share the network of the runtime.
+    None,
+    /// Processes are started in a fresh network namespace, with only a loopback interface, which
+    /// is brought up before exec.
+    Namespace,
+    /// Processes can only create `AF_UNIX` sockets, enforced with a seccomp filter.
+    Seccomp,
+}
+
+impl Default for NetworkIsolation {
+    fn default() -> Self {
+        NetworkIsolation::None
+    }
+}
+
+pub fn isolate(command: &mut process::Command, isolation: NetworkIsolation) -> io::Result<()> {
+    match isolation {
+        NetworkIsolation::None => (),
+        NetworkIsolation::Namespace => {
+            let unshare = Unshare::new();
+            unsafe { command.pre_exec(move || unshare.apply()) };
+        }
+        NetworkIsolation::Seccomp => {
+            let mut filter = socket_filter()?;
+            unsafe { command.pre_exec(move || install_filter(&mut filter)) };
+        }
+    }
+    Ok(())
+}
+
+/// Prepared in the parent, as formatting allocates.
+struct Unshare {
+    privileged: bool,
+    id_maps: Vec<(CString, CString)>,
+}
+
+impl Unshare {
+    fn new() -> Self {
+        let (uid, gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        let map = |path: &str, content: String| {
+            (CString::new(path).unwrap(), CString::new(content).unwrap())
+        };
+        Unshare {
+            privileged: uid == 0,
+            /* Map the current user to itself, so that workdir files stay accessible. */
+            id_maps: vec![
+                map("/proc/self/setgroups", "deny".to_string()),
+                map("/proc/self/uid_map", format!("{} {} 1", uid, uid)),
+                map("/proc/self/gid_map", format!("{} {} 1", gid, gid)),
+            ],
+        }
+    }
+
+    fn apply(&self) -> io::Result<()> {
+        if self.privileged {
+            check(unsafe { libc::unshare(libc::CLONE_NEWNET) })?;
+        } else {
+            check(unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET) })?;
+            for (path, content) in &self.id_maps {
+                write_file(path, content)?;
+            }
+        }
+        loopback_up()
+    }
+}
+
+const IFNAMSIZ: usize = 16;
+const SIOCGIFFLAGS: c_ulong = 0x8913;
+const SIOCSIFFLAGS: c_ulong = 0x8914;
+
+/// `struct ifreq`, restricted to the `ifr_flags` member of its union.
+#[repr(C)]
+struct IfReq {
+    name: [c_char; IFNAMSIZ],
+    flags: c_short,
+    _pad: [u8; 22],
+}
+
+/// A new network namespace starts with `lo` down; loopback traffic needs it up.
+fn loopback_up() -> io::Result<()> {
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    check(fd)?;
+    let mut req = IfReq {
+        name: [0; IFNAMSIZ],
+        flags: 0,
+        _pad: [0; 22],
+    };
+    req.name[0] = b'l' as c_char;
+    req.name[1] = b'o' as c_char;
+    let result = check(unsafe { libc::ioctl(fd, SIOCGIFFLAGS as _, &mut req as *mut IfReq) })
+        .and_then(|_| {
+            req.flags |= libc::IFF_UP as c_short;
+            check(unsafe { libc::ioctl(fd, SIOCSIFFLAGS as _, &req as *const IfReq) })
+        });
+    unsafe { libc::close(fd) };
+    result
+}
+
+fn write_file(path: &CString, content: &CString) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC) };
+    check(fd)?;
+    let bytes = content.as_bytes();
+    let written = unsafe { libc::write(fd, bytes.as_ptr() as *const libc::c_void, bytes.len()) };
+    unsafe { libc::close(fd) };
+    if written == bytes.len() as isize {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn check(ret: c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: c_ushort,
+    filter: *mut SockFilter,
+}
+
+/// `BPF_LD | BPF_W | BPF_ABS`
+const BPF_LD_W_ABS: u16 = 0x20;
+/// `BPF_JMP | BPF_JEQ | BPF_K`
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// `BPF_JMP | BPF_JGE | BPF_K`
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// `BPF_RET | BPF_K`
+const BPF_RET_K: u16 = 0x06;
+
+const SECCOMP_SET_MODE_FILTER: c_ulong = 1;
+const SECCOMP_MODE_FILTER: c_ulong = 2;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// Offsets in `struct seccomp_data`.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARG0: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+fn socket_filter() -> io::Result<Vec<SockFilter>> {
+    const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+    const SYS_SOCKET: u32 = 41;
+    /* io_uring can create sockets without going through socket(2). */
+    const SYS_IO_URING_SETUP: u32 = 425;
+    const SYS_IO_URING_ENTER: u32 = 426;
+    const SYS_IO_URING_REGISTER: u32 = 427;
+
+    let stmt = |code, k| SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let jump = |code, k, jt, jf| SockFilter { code, jt, jf, k };
+    Ok(vec![
+        /* 0 */ stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        /* 1 */ jump(BPF_JMP_JEQ_K, AUDIT_ARCH_X86_64, 1, 0),
+        /* 2 */ stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        /* 3 */ stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        /* 4 */ jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 6, 0),
+        /* 5 */ jump(BPF_JMP_JEQ_K, SYS_IO_URING_SETUP, 5, 0),
+        /* 6 */ jump(BPF_JMP_JEQ_K, SYS_IO_URING_ENTER, 4, 0),
+        /* 7 */ jump(BPF_JMP_JEQ_K, SYS_IO_URING_REGISTER, 3, 0),
+        /* 8 */ jump(BPF_JMP_JEQ_K, SYS_SOCKET, 0, 3),
+        /* 9 */ stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARG0),
+        /* 10 */ jump(BPF_JMP_JEQ_K, libc::AF_UNIX as u32, 1, 0),
+        /* 11 */ stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EACCES as u32),
+        /* 12 */ stmt(BPF_RET_K, SECCOMP_RET_ALLOW),
+    ])
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn socket_filter() -> io::Result<Vec<SockFilter>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "seccomp network isolation is only supported on x86_64",
+    ))
+}
+
+fn install_filter(filter: &mut [SockFilter]) -> io::Result<()> {
+    let prog = SockFprog {
+        len: filter.len() as c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            0,
+            &prog as *const SockFprog,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    /* Kernels older than 3.17 have no seccomp syscall. */
+    check(unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            &prog as *const SockFprog,
+        )
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_seccomp() -> io::Result<()> {
+        /* Opens an IPv4 socket in the child after isolation, failing the spawn if denied. */
+        let connect = |isolation| -> io::Result<process::ExitStatus> {
+            let mut command = process::Command::new("/bin/true");
+            isolate(&mut command, isolation)?;
+            unsafe {
+                command.pre_exec(|| {
+                    let fd = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+                    check(fd)?;
+                    check(libc::close(fd))
+                })
+            };
+            command.status()
+        };
+        assert!(connect(NetworkIsolation::None)?.success());
+        let err = connect(NetworkIsolation::Seccomp).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+
+        let mut command = process::Command::new("/bin/true");
+        isolate(&mut command, NetworkIsolation::Seccomp)?;
+        unsafe {
+            command.pre_exec(|| {
+                let ret = libc::syscall(libc::SYS_io_uring_setup, 1, std::ptr::null_mut::<u8>());
+                check(ret as c_int)
+            })
+        };
+        let err = command.status().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EACCES));
+        Ok(())
+    }
+
+    #[test]
+    fn test_namespace() -> io::Result<()> {
+        let mut command = process::Command::new("/bin/cat");
+        command.arg("/proc/self/net/dev");
+        isolate(&mut command, NetworkIsolation::Namespace)?;
+        /* Connecting a UDP socket needs a route, which exists only once lo is up. */
+        unsafe {
+            command.pre_exec(|| {
+                let fd = libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0);
+                check(fd)?;
+                let addr = libc::sockaddr_in {
+                    sin_family: libc::AF_INET as libc::sa_family_t,
+                    sin_port: 9u16.to_be(),
+                    sin_addr: libc::in_addr {
+                        s_addr: u32::from(std::net::Ipv4Addr::LOCALHOST).to_be(),
+                    },
+                    sin_zero: [0; 8],
+                };
+                let ret = libc::connect(
+                    fd,
+                    &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                );
+                libc::close(fd);
+                check(ret)
+            })
+        };
+        let output = command.output()?;
+        assert!(output.status.success());
+        let interfaces = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .skip(2)
+            .filter_map(|line| Some(line.split(':').next()?.trim().to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(interfaces, vec!["lo".to_string()]);
+        Ok(())
+    }
+}
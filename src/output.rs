@@ -0,0 +1,283 @@
+//! Capture of process stdout and stderr.
+//!
+//! Each output pipe is drained by a thread, so processes never block on a full pipe. Only the
+//! last bytes of each stream are kept in memory for the final process status; the whole stream
+//! can additionally be written to size-capped, rotated log files in the workdir.
+use futures::{channel::oneshot, future};
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// How long pipes may stay open after the process exits, e.g. held by its own children. The
+/// output read until then is reported.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Where the output of a single stream goes.
+#[derive(Clone)]
+pub struct Sink {
+    /// Size of the in-memory buffer reported in the process status. Nothing is kept if zero.
+    pub buffer_size: usize,
+    pub log: Option<Arc<Mutex<RotatingFile>>>,
+}
+
+pub struct Capture {
+    stdout: Option<Stream>,
+    stderr: Option<Stream>,
+}
+
+/// A pipe being drained by a thread.
+struct Stream {
+    tail: Arc<Mutex<VecDeque<u8>>>,
+    /// Resolved when the pipe is closed.
+    closed: oneshot::Receiver<()>,
+}
+
+impl Stream {
+    async fn closed(&mut self) {
+        let _ = (&mut self.closed).await;
+    }
+
+    fn tail(&self) -> Vec<u8> {
+        self.tail.lock().unwrap().iter().copied().collect()
+    }
+}
+
+impl Capture {
+    /// Starts reading pipes of `child`, if any were requested at spawn.
+    pub fn start(child: &mut process::Child, stdout: &Sink, stderr: &Sink) -> Self {
+        Capture {
+            stdout: child.stdout.take().map(|pipe| drain(pipe, stdout.clone())),
+            stderr: child.stderr.take().map(|pipe| drain(pipe, stderr.clone())),
+        }
+    }
+
+    /// Waits until the pipes are closed, at most `CLOSE_TIMEOUT`, and returns the buffered stdout
+    /// and stderr.
+    pub async fn finish(mut self) -> (Vec<u8>, Vec<u8>) {
+        let closed = future::join(
+            future::OptionFuture::from(self.stdout.as_mut().map(Stream::closed)),
+            future::OptionFuture::from(self.stderr.as_mut().map(Stream::closed)),
+        );
+        if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+            log::warn!("output still open after the process exited, reporting output read so far");
+        }
+        let tail = |stream: Option<Stream>| stream.map(|stream| stream.tail()).unwrap_or_default();
+        (tail(self.stdout), tail(self.stderr))
+    }
+}
+
+fn drain<R: Read + Send + 'static>(mut pipe: R, sink: Sink) -> Stream {
+    let tail = Arc::new(Mutex::new(VecDeque::with_capacity(
+        sink.buffer_size.min(64 * 1024),
+    )));
+    let (closed_tx, closed) = oneshot::channel();
+    let stream = Stream {
+        tail: Arc::clone(&tail),
+        closed,
+    };
+    thread::spawn(move || {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match pipe.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            let chunk = &buf[..n];
+            if let Some(log) = &sink.log {
+                if let Err(e) = log.lock().unwrap().write(chunk) {
+                    log::warn!("writing output log failed: {}", e);
+                }
+            }
+            let keep = chunk.len().min(sink.buffer_size);
+            let mut tail = tail.lock().unwrap();
+            let overflow = (tail.len() + keep).saturating_sub(sink.buffer_size);
+            tail.drain(..overflow);
+            tail.extend(&chunk[chunk.len() - keep..]);
+        }
+        let _ = closed_tx.send(());
+    });
+    stream
+}
+
+/// Append-only log file, rotated to `<path>.1`, `<path>.2`, ... when exceeding `max_size`.
+///
+/// The workdir is writable by processes, so links and other non-regular files placed at any of
+/// these paths are refused rather than followed.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    /// Number of rotated files to keep.
+    max_files: usize,
+    file: Option<fs::File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn new<P: Into<PathBuf>>(path: P, max_size: u64, max_files: usize) -> Self {
+        RotatingFile {
+            path: path.into(),
+            max_size,
+            max_files,
+            file: None,
+            size: 0,
+        }
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+                .open(&self.path)?;
+            let meta = file.metadata()?;
+            if !meta.is_file() {
+                return Err(not_regular(&self.path));
+            }
+            self.size = meta.len();
+            self.file = Some(file);
+        }
+        if self.size > 0 && self.size + data.len() as u64 > self.max_size {
+            self.rotate()?;
+            return self.write(data);
+        }
+        /* A single chunk larger than the cap is truncated rather than dropped. */
+        let len = data.len().min(self.max_size as usize);
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(&data[..len])?;
+        }
+        self.size += len as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        if self.max_files == 0 {
+            return fs::remove_file(&self.path);
+        }
+        /* Renaming replaces a link at the target rather than following it. */
+        for n in (1..self.max_files).rev() {
+            if is_regular(&rotated(n))? {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        if is_regular(&self.path)? {
+            fs::rename(&self.path, rotated(1))?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` is a regular file, `false` if it does not exist. Links are not followed.
+fn is_regular(path: &Path) -> io::Result<bool> {
+    match path.symlink_metadata() {
+        Ok(meta) if meta.is_file() => Ok(true),
+        Ok(_) => Err(not_regular(path)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn not_regular(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} is not a regular file", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_buffer() {
+        let sink = Sink {
+            buffer_size: 4,
+            log: None,
+        };
+        let finish = |data: &'static [u8]| {
+            let mut stream = drain(data, sink.clone());
+            async move {
+                stream.closed().await;
+                stream.tail()
+            }
+        };
+        assert_eq!(finish(b"0123456789").await, b"6789");
+        assert_eq!(finish(b"01").await, b"01");
+    }
+
+    #[tokio::test]
+    async fn test_finish() -> io::Result<()> {
+        let sink = Sink {
+            buffer_size: 64,
+            log: None,
+        };
+        /* The pipe is kept open by a background child after the process exits. */
+        let mut child = process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg("echo started; sleep 5 &")
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        let capture = Capture::start(&mut child, &sink, &sink);
+        child.wait()?;
+        let (out, err) = tokio::time::timeout(CLOSE_TIMEOUT * 2, capture.finish())
+            .await
+            .expect("finish blocked on an open pipe");
+        assert_eq!(out, b"started\n");
+        assert!(err.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotation() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("output-rotation-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("stdout.log");
+        let mut f = RotatingFile::new(&path, 4, 2);
+        for chunk in &[b"abc", b"def", b"ghi", b"jkl"] {
+            f.write(*chunk)?;
+        }
+        assert_eq!(fs::read(&path)?, b"jkl");
+        assert_eq!(fs::read(dir.join("stdout.log.1"))?, b"ghi");
+        assert_eq!(fs::read(dir.join("stdout.log.2"))?, b"def");
+        assert!(!dir.join("stdout.log.3").exists());
+
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_links() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("output-links-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let outside = dir.join("outside");
+        fs::write(&outside, b"outside")?;
+        let path = dir.join("stdout.log");
+        std::os::unix::fs::symlink(&outside, &path)?;
+        assert!(RotatingFile::new(&path, 4, 2).write(b"abc").is_err());
+
+        /* A link in place of a rotated file is refused as well. */
+        fs::remove_file(&path)?;
+        std::os::unix::fs::symlink(&outside, dir.join("stdout.log.1"))?;
+        let mut f = RotatingFile::new(&path, 4, 2);
+        f.write(b"abc")?;
+        assert!(f.write(b"def").is_err());
+        assert_eq!(fs::read(&outside)?, b"outside");
+
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,120 @@
+//! Task package descriptor.
+//!
+//! Task packages are zip archives with a `manifest.json` descriptor, as built by
+//! `cargo ya-wasi-pkg`. A package run as a service declares its daemon entry point there:
+//!
+//! ```json
+//! {
+//!   "id": "...",
+//!   "name": "trustless-voting-mgr",
+//!   "entry-points": [{ "id": "trustless-voting-mgr", "wasm-path": "trustless-voting-mgr.wasm" }],
+//!   "mount-points": [{ "private": "/private" }],
+//!   "service": { "entry-point": "/work/trustless-voting-mgr", "args": ["serve"] }
+//! }
+//! ```
+use serde::Deserialize;
+use std::{fs, io, path::Path};
+
+const DESCRIPTOR: &str = "manifest.json";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Descriptor {
+    #[serde(default)]
+    pub service: Option<ServiceEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServiceEntry {
+    /// Daemon started on `start`.
+    pub entry_point: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+impl Descriptor {
+    /// Reads the descriptor of a task package. Packages which are not zip archives, such as
+    /// Graphene images, or which have no descriptor have none.
+    pub fn from_package(path: &Path) -> io::Result<Option<Self>> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid package {}: {}", path.display(), e),
+            )
+        };
+        let mut archive = match zip::ZipArchive::new(fs::File::open(path)?) {
+            Ok(archive) => archive,
+            Err(e) => {
+                log::debug!("package {} is not a zip archive: {}", path.display(), e);
+                return Ok(None);
+            }
+        };
+        let descriptor = match archive.by_name(DESCRIPTOR) {
+            Ok(descriptor) => descriptor,
+            Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+            Err(e) => return Err(invalid(&e)),
+        };
+        serde_json::from_reader(io::BufReader::new(descriptor))
+            .map(Some)
+            .map_err(|e| invalid(&e))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use std::io::Write;
+
+    /// Writes a package with the given descriptor.
+    pub fn write_package(path: &Path, descriptor: &str) -> io::Result<()> {
+        write_zip(path, DESCRIPTOR, descriptor)
+    }
+
+    fn write_zip(path: &Path, name: &str, content: &str) -> io::Result<()> {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path)?);
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        zip.start_file(name, options)?;
+        zip.write_all(content.as_bytes())?;
+        zip.finish()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_descriptor() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("package-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("voting.ywasi");
+
+        write_package(
+            &path,
+            r#"{"id": "1", "name": "voting", "entry-points": [],
+                "service": {"entry-point": "/work/app", "args": ["serve"]}}"#,
+        )?;
+        assert_eq!(
+            Descriptor::from_package(&path)?.unwrap().service,
+            Some(ServiceEntry {
+                entry_point: "/work/app".into(),
+                args: vec!["serve".into()],
+            })
+        );
+
+        write_package(
+            &path,
+            r#"{"id": "1", "name": "voting", "entry-points": []}"#,
+        )?;
+        assert!(Descriptor::from_package(&path)?.unwrap().service.is_none());
+
+        write_package(&path, r#"{"service": {"entry": "/work/app"}}"#)?;
+        assert!(Descriptor::from_package(&path).is_err());
+
+        /* Packages of other runtimes. */
+        write_zip(&path, "image.bin", "")?;
+        assert!(Descriptor::from_package(&path)?.is_none());
+        fs::write(&path, b"not a zip")?;
+        assert!(Descriptor::from_package(&path)?.is_none());
+
+        fs::remove_dir_all(&dir)
+    }
+}
@@ -0,0 +1,223 @@
+//! Workdir disk usage accounting.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/// Entry point of the built-in usage query, `@usage`. The usage report is the stdout of its
+/// final process status.
+pub const COMMAND: &str = "@usage";
+
+pub struct WorkdirQuota {
+    path: PathBuf,
+    limit: u64,
+    usage: AtomicU64,
+    /// Entries not read by the last scan.
+    errors: AtomicU64,
+    exceeded: AtomicBool,
+}
+
+impl WorkdirQuota {
+    pub fn new<P: Into<PathBuf>>(path: P, limit: u64) -> Self {
+        Self {
+            path: path.into(),
+            limit,
+            usage: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            exceeded: AtomicBool::new(false),
+        }
+    }
+
+    /// Usage in bytes, as of the last scan.
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+
+    /// Rescans the workdir, returns `true` if usage is over the limit. Usage of a workdir not
+    /// read entirely is unknown, and counts as over the limit.
+    pub fn scan(&self) -> bool {
+        let usage = dir_usage(&self.path);
+        let exceeded = usage.bytes > self.limit || usage.errors > 0;
+        self.usage.store(usage.bytes, Ordering::Relaxed);
+        self.errors.store(usage.errors, Ordering::Relaxed);
+        self.exceeded.store(exceeded, Ordering::Relaxed);
+        exceeded
+    }
+
+    pub fn describe(&self) -> String {
+        match self.errors.load(Ordering::Relaxed) {
+            0 => format!(
+                "workdir quota exceeded: {} of {} bytes used",
+                self.usage(),
+                self.limit
+            ),
+            errors => format!(
+                "workdir quota exceeded: {} of {} bytes used, {} entries not readable",
+                self.usage(),
+                self.limit,
+                errors
+            ),
+        }
+    }
+}
+
+/// Usage report returned by the usage query, e.g. `{"usage":150,"limit":1024,"exceeded":false}`.
+pub fn report(usage: u64, limit: Option<u64>, exceeded: bool) -> String {
+    serde_json::json!({
+        "usage": usage,
+        "limit": limit,
+        "exceeded": exceeded,
+    })
+    .to_string()
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    /// Directories and entries which could not be read, and are not counted.
+    pub errors: u64,
+}
+
+/// Total size of regular files under `path`. Symbolic links are not followed.
+pub fn dir_usage<P: AsRef<Path>>(path: P) -> Usage {
+    let mut usage = Usage::default();
+    let mut pending = vec![path.as_ref().to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            /* Files may disappear while we are scanning. */
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                log::debug!("reading {} failed: {}", dir.display(), e);
+                usage.errors += 1;
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    log::debug!("reading {} failed: {}", dir.display(), e);
+                    usage.errors += 1;
+                    break;
+                }
+            };
+            let meta = match path.symlink_metadata() {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    log::debug!("reading {} failed: {}", path.display(), e);
+                    usage.errors += 1;
+                    continue;
+                }
+            };
+            if meta.is_dir() {
+                pending.push(path);
+            } else if meta.is_file() {
+                usage.bytes += meta.len();
+            }
+        }
+    }
+    usage
+}
+
+/// Parses a size with an optional binary unit suffix, e.g. `512M` or `2G`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 10),
+        Some('M') => (&s[..s.len() - 1], 20),
+        Some('G') => (&s[..s.len() - 1], 30),
+        Some('T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let value: u64 = digits
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid size {:?}: {}", s, e))?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size too large: {}", s))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("100000000T").is_err());
+    }
+
+    #[test]
+    fn test_scan() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("workdir-quota-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("a"), vec![0u8; 100])?;
+        fs::write(dir.join("sub/b"), vec![0u8; 50])?;
+
+        let quota = WorkdirQuota::new(&dir, 120);
+        assert!(quota.scan());
+        assert_eq!(quota.usage(), 150);
+        fs::remove_file(dir.join("a"))?;
+        assert!(!quota.scan());
+        assert!(!quota.exceeded());
+
+        /* An unreadable directory does not hide the rest, but fails the scan. */
+        fs::create_dir(dir.join("locked"))?;
+        fs::write(dir.join("locked/c"), vec![0u8; 10])?;
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o000))?;
+        let readable = fs::read_dir(dir.join("locked")).is_ok();
+        let usage = dir_usage(&dir);
+        fs::set_permissions(dir.join("locked"), fs::Permissions::from_mode(0o755))?;
+        /* Directory permissions do not apply to root. */
+        if !readable {
+            assert_eq!(
+                usage,
+                Usage {
+                    bytes: 50,
+                    errors: 1
+                }
+            );
+            assert!(WorkdirQuota::new(&dir, 120).scan());
+        }
+        assert_eq!(dir_usage(dir.join("missing")), Usage::default());
+
+        fs::remove_dir_all(&dir)
+    }
+
+    #[test]
+    fn test_report() {
+        let report = |usage, limit, exceeded| -> serde_json::Value {
+            serde_json::from_str(&super::report(usage, limit, exceeded)).unwrap()
+        };
+        assert_eq!(
+            report(150, Some(120), true),
+            serde_json::json!({"usage": 150, "limit": 120, "exceeded": true})
+        );
+        assert_eq!(
+            report(100, Some(120), false),
+            serde_json::json!({"usage": 100, "limit": 120, "exceeded": false})
+        );
+        assert_eq!(
+            report(100, None, false),
+            serde_json::json!({"usage": 100, "limit": null, "exceeded": false})
+        );
+    }
+}
@@ -0,0 +1,323 @@
+//! Supervision of a long-running service process.
+//!
+//! Packages declaring a service are deployed with the empty start mode. Their daemon entry point
+//! is started on `start`, restarted according to its restart policy with an exponential
+//! backoff, and stopped on shutdown.
+use crate::{logging::Span, output};
+use futures::lock::Mutex;
+use serde::Deserialize;
+use std::{
+    io, process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::OnFailure
+    }
+}
+
+impl RestartPolicy {
+    fn restart(self, status: Option<process::ExitStatus>) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => !matches!(status, Some(status) if status.success()),
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+/// Restart delays, doubled after each consecutive restart.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Consecutive restarts after which the service is given up, unlimited if `None`.
+    pub max_restarts: Option<u32>,
+}
+
+impl Backoff {
+    /// Delay before the `n`-th consecutive restart, counted from 1.
+    fn delay(&self, n: u32) -> Duration {
+        let factor = 1u32.checked_shl(n.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial
+            .checked_mul(factor)
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+pub struct Supervisor {
+    policy: RestartPolicy,
+    backoff: Backoff,
+    span: Span,
+    child: Mutex<Option<process::Child>>,
+    stopping: AtomicBool,
+    /// Set while `run` supervises the service, so that stopping waits only for a running one.
+    running: AtomicBool,
+}
+
+impl Supervisor {
+    pub fn new(entry_point: &str, policy: RestartPolicy, backoff: Backoff) -> Self {
+        Supervisor {
+            policy,
+            backoff,
+            span: Span::new("service").with("entry", entry_point),
+            child: Mutex::new(None),
+            stopping: AtomicBool::new(false),
+            running: AtomicBool::new(false),
+        }
+    }
+
+    /// Runs the service until it is stopped or not restarted anymore. `spawn` starts a single
+    /// instance of the service.
+    pub async fn run<F>(&self, spawn: F)
+    where
+        F: Fn() -> Result<(process::Child, output::Capture), String>,
+    {
+        /* Set before `stopping` is checked, so that `stop` either waits for this run or this run
+         * sees that the service is stopping. */
+        self.running.store(true, Ordering::SeqCst);
+        let mut restarts = 0;
+        while !self.stopping.load(Ordering::SeqCst) {
+            let started = Instant::now();
+            let status = match spawn() {
+                Ok((mut child, output)) => {
+                    let span = self.span.clone().with("pid", child.id());
+                    span.log(log::Level::Info, format_args!("service started"));
+                    let mut slot = self.child.lock().await;
+                    if self.stopping.load(Ordering::SeqCst) {
+                        /* Stopped while starting. */
+                        let _ = child.kill();
+                    }
+                    *slot = Some(child);
+                    drop(slot);
+                    let status = self.wait().await;
+                    output.finish().await;
+                    span.log(
+                        log::Level::Info,
+                        format_args!("service exited: {:?}", status),
+                    );
+                    status
+                }
+                Err(e) => {
+                    self.span.log(
+                        log::Level::Error,
+                        format_args!("starting service failed: {}", e),
+                    );
+                    None
+                }
+            };
+            if self.stopping.load(Ordering::SeqCst) || !self.policy.restart(status) {
+                break;
+            }
+            /* A service running for longer than the maximum delay is considered healthy. */
+            if started.elapsed() >= self.backoff.max {
+                restarts = 0;
+            }
+            restarts += 1;
+            if matches!(self.backoff.max_restarts, Some(max) if restarts > max) {
+                self.span.log(
+                    log::Level::Error,
+                    format_args!("service failed {} times in a row, giving up", restarts),
+                );
+                break;
+            }
+            let delay = self.backoff.delay(restarts);
+            self.span.log(
+                log::Level::Warn,
+                format_args!("restarting service in {:?}", delay),
+            );
+            self.pause(delay).await;
+        }
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    /// Waits for `delay` before a restart, returning early once stopping.
+    async fn pause(&self, delay: Duration) {
+        let resume = Instant::now() + delay;
+        while !self.stopping.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= resume {
+                break;
+            }
+            tokio::time::delay_for((resume - now).min(Duration::from_millis(100))).await;
+        }
+    }
+
+    async fn wait(&self) -> Option<process::ExitStatus> {
+        loop {
+            {
+                let mut child = self.child.lock().await;
+                let status = match child.as_mut().map(process::Child::try_wait) {
+                    Some(Ok(None)) => None,
+                    Some(Ok(Some(status))) => Some(Some(status)),
+                    Some(Err(e)) => {
+                        log::warn!("waiting for service failed: {}", e);
+                        Some(None)
+                    }
+                    None => Some(None),
+                };
+                if let Some(status) = status {
+                    *child = None;
+                    return status;
+                }
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Pid of the running instance, if any.
+    #[cfg(test)]
+    pub async fn pid(&self) -> Option<u32> {
+        self.child.lock().await.as_ref().map(process::Child::id)
+    }
+
+    /// Kills the running instance, which is then restarted according to the restart policy.
+    pub async fn kill(&self) -> io::Result<()> {
+        match self.child.lock().await.as_mut() {
+            Some(child) => child.kill(),
+            None => Ok(()),
+        }
+    }
+
+    /// Kills the service and waits until it is reaped, for at most `timeout`. A pending restart
+    /// is cancelled, and a service not started yet is not started anymore.
+    pub async fn stop(&self, timeout: Duration) -> io::Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        if let Some(child) = self.child.lock().await.as_mut() {
+            child.kill()?;
+        }
+        let deadline = Instant::now() + timeout;
+        while self.running.load(Ordering::SeqCst) {
+            if Instant::now() > deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "service not stopped in time",
+                ));
+            }
+            tokio::time::delay_for(Duration::from_millis(100)).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+
+    fn status(code: i32) -> Option<process::ExitStatus> {
+        Command::new("/bin/sh")
+            .args(&["-c", &format!("exit {}", code)])
+            .status()
+            .ok()
+    }
+
+    #[test]
+    fn test_policy() {
+        assert!(RestartPolicy::OnFailure.restart(status(1)));
+        assert!(RestartPolicy::OnFailure.restart(None));
+        assert!(!RestartPolicy::OnFailure.restart(status(0)));
+        assert!(RestartPolicy::Always.restart(status(0)));
+        assert!(!RestartPolicy::Never.restart(status(1)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(10),
+            max_restarts: None,
+        };
+        let delays: Vec<_> = [1, 2, 3, 4, 5, 40]
+            .iter()
+            .map(|n| backoff.delay(*n).as_secs())
+            .collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 10, 10]);
+    }
+
+    fn spawn(script: &str) -> Result<(process::Child, output::Capture), String> {
+        let sink = output::Sink {
+            buffer_size: 0,
+            log: None,
+        };
+        let mut child = Command::new("/bin/sh")
+            .args(&["-c", script])
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        let output = output::Capture::start(&mut child, &sink, &sink);
+        Ok((child, output))
+    }
+
+    #[tokio::test]
+    async fn test_supervisor() -> io::Result<()> {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+            max_restarts: Some(2),
+        };
+        let supervisor = Supervisor::new("sh", RestartPolicy::OnFailure, backoff.clone());
+        let starts = std::cell::Cell::new(0);
+        supervisor
+            .run(|| {
+                starts.set(starts.get() + 1);
+                spawn("exit 1")
+            })
+            .await;
+        assert_eq!(starts.get(), 3);
+
+        let supervisor = std::sync::Arc::new(Supervisor::new("sh", RestartPolicy::Always, backoff));
+        let running = std::sync::Arc::clone(&supervisor);
+        let handle = tokio::spawn(async move { running.run(|| spawn("sleep 100")).await });
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        supervisor.stop(Duration::from_secs(5)).await?;
+        handle.await.unwrap();
+
+        /* Stopped while waiting to restart. */
+        let backoff = Backoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+            max_restarts: None,
+        };
+        let supervisor = std::sync::Arc::new(Supervisor::new("sh", RestartPolicy::Always, backoff));
+        let running = std::sync::Arc::clone(&supervisor);
+        let handle = tokio::spawn(async move { running.run(|| spawn("exit 1")).await });
+        tokio::time::delay_for(Duration::from_millis(500)).await;
+        supervisor.stop(Duration::from_secs(1)).await?;
+        handle.await.unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stop_before_start() -> io::Result<()> {
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+            max_restarts: None,
+        };
+        let supervisor = Supervisor::new("sh", RestartPolicy::Always, backoff);
+        let stopping = Instant::now();
+        supervisor.stop(Duration::from_secs(5)).await?;
+        assert!(stopping.elapsed() < Duration::from_secs(1));
+
+        /* Not started once stopped. */
+        let starts = std::cell::Cell::new(0);
+        supervisor
+            .run(|| {
+                starts.set(starts.get() + 1);
+                spawn("sleep 100")
+            })
+            .await;
+        assert_eq!(starts.get(), 0);
+        Ok(())
+    }
+}
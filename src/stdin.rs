@@ -0,0 +1,225 @@
+//! Stdin supply for runtime processes.
+//!
+//! The runtime API has no notion of stdin, so it is requested with a wrapper command:
+//!
+//! ```text
+//! @stdin [--file <path> | --data <hex> | --text <string>]... -- <bin> [args...]
+//! ```
+//!
+//! `--file` connects stdin to a file in the workdir. `--data` and `--text` chunks are
+//! concatenated and written to the process, after which stdin is closed (EOF).
+//! Without the wrapper, stdin is connected to `/dev/null`.
+use std::{
+    fs, io,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Component, Path, PathBuf},
+    process,
+};
+
+pub const WRAPPER: &str = "@stdin";
+
+#[derive(Debug, PartialEq)]
+pub enum StdinSource {
+    Null,
+    File(PathBuf),
+    Data(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Invocation {
+    pub bin: String,
+    /// Arguments, excluding `argv[0]`.
+    pub args: Vec<String>,
+    pub stdin: StdinSource,
+}
+
+impl Invocation {
+    /// Interprets `bin` and `args` (including `argv[0]`) of a `RunProcess` request.
+    pub fn parse(bin: String, args: Vec<String>) -> Result<Self, String> {
+        let mut args = args.into_iter().skip(1);
+        if bin != WRAPPER {
+            return Ok(Invocation {
+                bin,
+                args: args.collect(),
+                stdin: StdinSource::Null,
+            });
+        }
+
+        let mut file = None;
+        let mut data = None;
+        loop {
+            let opt = args
+                .next()
+                .ok_or_else(|| format!("{}: missing `-- <bin>`", WRAPPER))?;
+            if opt == "--" {
+                break;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{}: missing value for {}", WRAPPER, opt))?;
+            match opt.as_str() {
+                "--file" => file = Some(workdir_relative(&value)?),
+                "--data" => data.get_or_insert_with(Vec::new).extend(
+                    hex::decode(&value)
+                        .map_err(|e| format!("{}: invalid --data: {}", WRAPPER, e))?,
+                ),
+                "--text" => data
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(value.as_bytes()),
+                _ => return Err(format!("{}: unknown option {}", WRAPPER, opt)),
+            }
+        }
+        let stdin = match (file, data) {
+            (Some(_), Some(_)) => {
+                return Err(format!(
+                    "{}: --file cannot be combined with --data or --text",
+                    WRAPPER
+                ))
+            }
+            (Some(file), None) => StdinSource::File(file),
+            (None, Some(data)) => StdinSource::Data(data),
+            (None, None) => StdinSource::Null,
+        };
+        let bin = args
+            .next()
+            .ok_or_else(|| format!("{}: missing <bin>", WRAPPER))?;
+        Ok(Invocation {
+            bin,
+            args: args.collect(),
+            stdin,
+        })
+    }
+}
+
+impl StdinSource {
+    pub fn stdio(&self, work_dir: &Path) -> io::Result<process::Stdio> {
+        Ok(match self {
+            StdinSource::Null => process::Stdio::null(),
+            StdinSource::File(path) => open_file(work_dir, path)?.into(),
+            StdinSource::Data(_) => process::Stdio::piped(),
+        })
+    }
+
+    /// Writes the data to a freshly spawned `child` in the background, then closes its stdin.
+    pub fn feed(self, child: &mut process::Child) {
+        let (data, mut stdin) = match (self, child.stdin.take()) {
+            (StdinSource::Data(data), Some(stdin)) => (data, stdin),
+            _ => return,
+        };
+        let pid = child.id();
+        std::thread::spawn(move || {
+            /* The process may exit without reading its input. */
+            if let Err(e) = stdin.write_all(&data) {
+                log::debug!("writing stdin of process (pid: {}) failed: {}", pid, e);
+            }
+        });
+    }
+}
+
+/// Opens a regular file in the workdir without following symlinks, which could lead out of it.
+fn open_file(work_dir: &Path, path: &Path) -> io::Result<fs::File> {
+    let not_regular = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a regular file", path.display()),
+        )
+    };
+    let mut dir = work_dir.to_path_buf();
+    if let Some(parent) = path.parent() {
+        for component in parent.components() {
+            dir.push(component);
+            if fs::symlink_metadata(&dir)?.file_type().is_symlink() {
+                return Err(not_regular());
+            }
+        }
+    }
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(work_dir.join(path))
+        .map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => not_regular(),
+            _ => e,
+        })?;
+    if !file.metadata()?.is_file() {
+        return Err(not_regular());
+    }
+    Ok(file)
+}
+
+/// Rejects paths escaping the workdir.
+fn workdir_relative(path: &str) -> Result<PathBuf, String> {
+    let path = PathBuf::from(path);
+    if path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        Ok(path)
+    } else {
+        Err(format!(
+            "{}: stdin file must be relative to the workdir: {}",
+            WRAPPER,
+            path.display()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Invocation::parse("/bin/cat".into(), args(&["cat", "-n"])),
+            Ok(Invocation {
+                bin: "/bin/cat".into(),
+                args: args(&["-n"]),
+                stdin: StdinSource::Null,
+            })
+        );
+        assert_eq!(
+            Invocation::parse(
+                WRAPPER.into(),
+                args(&["", "--text", "a\n", "--data", "620a", "--", "/bin/cat", "-n"])
+            ),
+            Ok(Invocation {
+                bin: "/bin/cat".into(),
+                args: args(&["-n"]),
+                stdin: StdinSource::Data(b"a\nb\n".to_vec()),
+            })
+        );
+        assert_eq!(
+            Invocation::parse(WRAPPER.into(), args(&["", "--file", "in.txt", "--", "cat"]))
+                .map(|i| i.stdin),
+            Ok(StdinSource::File("in.txt".into()))
+        );
+        assert!(
+            Invocation::parse(WRAPPER.into(), args(&["", "--file", "../in", "--", "cat"])).is_err()
+        );
+        assert!(Invocation::parse(WRAPPER.into(), args(&["", "--text", "a"])).is_err());
+        assert!(Invocation::parse(WRAPPER.into(), args(&["", "--"])).is_err());
+    }
+
+    #[test]
+    fn test_file() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("stdin-file-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("sub/in.txt"), "in")?;
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("passwd"))?;
+        std::os::unix::fs::symlink("/etc", dir.join("etc"))?;
+
+        let stdin = |path: &str| StdinSource::File(path.into()).stdio(&dir);
+        assert!(stdin("sub/in.txt").is_ok());
+        for path in &["passwd", "etc/passwd", "sub", "missing"] {
+            assert!(stdin(path).is_err(), "{}", path);
+        }
+
+        fs::remove_dir_all(&dir)
+    }
+}
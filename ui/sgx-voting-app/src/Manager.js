@@ -43,7 +43,12 @@ export async function send_vote(address, sender, vote) {
         body: new Uint8Array(vote)
     }));
 
-    return await result.arrayBuffer();
+    const receipt = {
+        seq: result.headers.get('X-Receipt-Seq'),
+        ballotHash: result.headers.get('X-Receipt-Ballot-Hash'),
+        signature: result.headers.get('X-Receipt-Signature'),
+    };
+    return {response: await result.arrayBuffer(), receipt};
 }
 
 
@@ -39,7 +39,7 @@ class Details extends React.Component {
                     this.setState({pending: 'register'})
                     let {sessionKey, accountId, signature} = await account.signRegistration(session.contract, session.votingId, session.managerAddress);
                     const ticket = await send_register(managerAddress, accountId, signature, sessionKey);
-                    const {managerPubKey, resolvedAddress} = account.validateTicket(session.contract, session.votingId, accountId, ticket);
+                    const {managerPubKey, resolvedAddress} = account.validateTicket(session.contract, session.votingId, accountId, ticket, session.eligibleRoot);
                     console.log('isValid', resolvedAddress == managerAddress, 'managerPubKey', managerPubKey, 'a', resolvedAddress, managerAddress);
                     const newSession = await get_session(managerAddress);
                     this.setState({session: newSession});
@@ -87,15 +87,25 @@ class Details extends React.Component {
                     this.setState({pending: 'vote'});
                     const accountId = account.accountId;
                     const ticket = session.tickets[accountId.slice(2)];
-                    const {managerPubKey, resolvedAddress} = account.validateTicket(session.contract, session.votingId, accountId, ticket);
+                    const {managerPubKey, resolvedAddress} = account.validateTicket(session.contract, session.votingId, accountId, ticket, session.eligibleRoot);
                     if (resolvedAddress != session.managerAddress) {
                         console.error('unable to resolve pubkey')
                     }
                     else {
-                        const messageBytes = await account.encryptVote(this.state.decision, ticket, managerPubKey);
-                        let result = await account.decryptVote(managerPubKey, messageBytes);
-                        console.log('result', result);
-                        await send_vote(session.managerAddress, accountId.slice(2), messageBytes);
+                        const election = {
+                            contract: session.contract,
+                            votingId: session.votingId,
+                            sender: accountId,
+                            manager: session.managerAddress,
+                        };
+                        const messageBytes = await account.encryptVote(this.state.decision, election, managerPubKey);
+                        const {response, receipt} = await send_vote(session.managerAddress, accountId.slice(2), messageBytes);
+                        const result = await account.decryptVote(managerPubKey, election, response);
+                        console.log('result', new TextDecoder().decode(result));
+                        const signer = account.validateReceipt(election, receipt);
+                        if (signer !== session.managerAddress.replace(/^0x/, '').toLowerCase()) {
+                            console.error('receipt not signed by the manager', receipt);
+                        }
                     }
                     const newSession = await get_session(managerAddress);
                     this.setState({session: newSession});
@@ -124,6 +134,8 @@ class Details extends React.Component {
             <tr><th>abstain</th><td>{state.votes['0'] || 0}</td></tr>
             <tr><th>yea</th><td>{state.votes['1'] || 0}</td></tr>
             <tr><th>no</th><td>{state.votes['2'] || 0}</td></tr>
+            <tr><th>abstentions</th><td>{state.abstentions}</td></tr>
+            <tr><th>eligible hash</th><td><code>{state.eligibleHash}</code></td></tr>
             <tr><th>signature</th><td>
                 <code>{state.signature.slice(0, 64)}
                 </code> <code>{state.signature.slice(64, 128)}
@@ -5,6 +5,7 @@ import {keccak256} from "ethereum-cryptography/keccak";
 import {sha256} from "ethereum-cryptography/sha256";
 import {secp256k1} from 'ethereum-cryptography/secp256k1';
 import {ec as EC} from 'elliptic';
+import {decryptResponse, encryptBallot} from './ballot';
 
 export let account = null;
 
@@ -86,17 +87,24 @@ class Account {
         return Array.from(bytes).map((ch) => ('00' + ch.toString(16)).slice(-2)).join('')
     }
 
-    validateTicket(contract, votingId, sender, signature) {
-        const head = keccak256("SgxVotingTicket(address,bytes,address)");
+    validateTicket(contract, votingId, sender, signature, eligibleRoot) {
+        // Tickets of sessions with a Merkle root of eligible voters sign the root along.
+        const head = eligibleRoot
+            ? keccak256("SgxVotingTicket(address,bytes,address,bytes32)")
+            : keccak256("SgxVotingTicket(address,bytes,address)");
         let utf8 = new TextEncoder('utf-8');
         let vid = utf8.encode(votingId)
 
-        let bx = Buffer.concat([
+        let parts = [
             head,
             Buffer.from(this.hex2a(contract)),
             Buffer.from(vid),
             Buffer.from(this.hex2a(sender))
-        ]);
+        ];
+        if (eligibleRoot) {
+            parts.push(Buffer.from(this.hex2a(eligibleRoot)));
+        }
+        let bx = Buffer.concat(parts);
         let h = keccak256(bx);
         //let signature_bytes = this.hex2a(signature);
         let j = parseInt(signature.slice(-2), 2);
@@ -107,6 +115,27 @@ class Account {
         return {managerPubKey, resolvedAddress};
     }
 
+    // Recovers the signer of the receipt of an accepted ballot, from the X-Receipt-* headers.
+    validateReceipt(election, receipt) {
+        const {contract, votingId, sender} = election;
+        const {seq, ballotHash, signature} = receipt;
+        let utf8 = new TextEncoder('utf-8');
+        let bx = Buffer.concat([
+            keccak256("SgxVotingReceipt(address,bytes,address,bytes32,uint64)"),
+            Buffer.from(this.hex2a(contract)),
+            Buffer.from(utf8.encode(votingId)),
+            Buffer.from(this.hex2a(sender)),
+            Buffer.from(this.hex2a(ballotHash)),
+            Buffer.from(this.hex2a(seq.padStart(64, '0'))),
+        ]);
+        let h = keccak256(bx);
+        let j = parseInt(signature.slice(-2), 16);
+        let r = signature.slice(0, 64);
+        let s = signature.slice(64, 64*2);
+        const managerPubKey = this.ec.recoverPubKey(h, {r, s}, j >= 27 ? j - 27 : j);
+        return this.pubToAddress(managerPubKey);
+    }
+
     hex2a(hex) {
         if (hex.startsWith('0x')) {
             hex = hex.substring(2);
@@ -145,42 +174,13 @@ class Account {
         return sha256(ss_bytes);
     }
 
-    async encryptVote(decision, ticket, mgrPubKey) {
-        const key_bytes = this.sharedSecret(mgrPubKey);
-        let key = await crypto.subtle.importKey('raw', key_bytes, 'AES-GCM', false, ['encrypt']);
-        const bytes = new Uint8Array([decision, 0, 0, 0]);
-        const iv = window.crypto.getRandomValues(new Uint8Array(12));
-        const encryptedMesssage = await window.crypto.subtle.encrypt(
-            {
-                name: "AES-GCM",
-                iv: iv
-            },
-            key,
-            bytes
-        );
-        console.log('message', bytes, 'iv', iv, 'enc', encryptedMesssage);
-
-        return [ ... iv, ... new Uint8Array(encryptedMesssage)];
+    // `election` holds the contract, the voting id, the sender and the manager address.
+    async encryptVote(decision, election, mgrPubKey) {
+        return await encryptBallot(this.sharedSecret(mgrPubKey), election, decision);
     }
 
-    async decryptVote(mgrPubKey, message) {
-        message = Array.from(message);
-
-        const key_bytes = this.sharedSecret(mgrPubKey);
-
-        let key = await crypto.subtle.importKey('raw', key_bytes, 'AES-GCM', false, ['decrypt']);
-
-        const iv = new Uint8Array(message.slice(null, 12));
-        const encrypted = new Uint8Array(message.slice(12));
-        console.log('message', message, 'iv', iv, 'enc', encrypted);
-        return await window.crypto.subtle.decrypt(
-            {
-                name: "AES-GCM",
-                iv: iv
-            },
-            key,
-            encrypted
-        );
+    async decryptVote(mgrPubKey, election, response) {
+        return await decryptResponse(this.sharedSecret(mgrPubKey), election, response);
     }
 
     genPairs() {
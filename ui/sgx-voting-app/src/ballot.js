@@ -0,0 +1,96 @@
+// Ballots of the voting manager: the version byte, the nonce and the AES-256-GCM ciphertext of
+// the vote, authenticated along with the election and the sender. Ballots and receipts are
+// encrypted with separate keys derived by HKDF-SHA256 from the shared secret.
+export const BALLOT_VERSION = 3;
+
+const BALLOT_INFO = 'SgxVoting ballot';
+const RECEIPT_INFO = 'SgxVoting receipt';
+
+function hexBytes(hex) {
+    if (hex.startsWith('0x')) {
+        hex = hex.substring(2);
+    }
+    const bytes = new Uint8Array(hex.length / 2);
+    for (let i = 0; i < bytes.length; ++i) {
+        bytes[i] = parseInt(hex.substring(i * 2, i * 2 + 2), 16);
+    }
+    return bytes;
+}
+
+function concat(parts) {
+    const bytes = new Uint8Array(parts.reduce((len, part) => len + part.length, 0));
+    let offset = 0;
+    for (const part of parts) {
+        bytes.set(part, offset);
+        offset += part.length;
+    }
+    return bytes;
+}
+
+// Binds a ballot to the election and its sender, as `Voting::ballot_aad` of the manager.
+export function ballotAad(election) {
+    const {contract, votingId, sender, manager} = election;
+    return concat([
+        new Uint8Array([BALLOT_VERSION]),
+        hexBytes(contract),
+        new TextEncoder().encode(votingId),
+        hexBytes(sender),
+        hexBytes(manager),
+    ]);
+}
+
+// Identifies the election in ballot keys, as `Voting::scope` of the manager.
+function scope(election) {
+    const {contract, votingId, manager} = election;
+    return concat([hexBytes(contract), hexBytes(manager), new TextEncoder().encode(votingId)]);
+}
+
+// Key of the `info` direction, as `Voting::session_cipher` of the manager.
+async function sessionKey(sharedSecret, election, info, usage) {
+    const secret = await crypto.subtle.importKey('raw', new Uint8Array(sharedSecret), 'HKDF', false, ['deriveKey']);
+    return await crypto.subtle.deriveKey(
+        {
+            name: 'HKDF',
+            hash: 'SHA-256',
+            salt: scope(election),
+            info: new TextEncoder().encode(info),
+        },
+        secret,
+        {name: 'AES-GCM', length: 256},
+        false,
+        [usage]
+    );
+}
+
+// Encrypts `decision` for the manager. `election` holds the hex addresses of the contract, the
+// sender and the manager, along with the voting id.
+export async function encryptBallot(sharedSecret, election, decision) {
+    const key = await sessionKey(sharedSecret, election, BALLOT_INFO, 'encrypt');
+    const vote = new Uint8Array(4);
+    new DataView(vote.buffer).setUint32(0, Number(decision), true);
+    const iv = crypto.getRandomValues(new Uint8Array(12));
+    const encrypted = await crypto.subtle.encrypt(
+        {
+            name: 'AES-GCM',
+            iv,
+            additionalData: ballotAad(election),
+        },
+        key,
+        vote
+    );
+    return [BALLOT_VERSION, ...iv, ...new Uint8Array(encrypted)];
+}
+
+// Decrypts the response of the manager to an accepted ballot.
+export async function decryptResponse(sharedSecret, election, response) {
+    const key = await sessionKey(sharedSecret, election, RECEIPT_INFO, 'decrypt');
+    const bytes = new Uint8Array(response);
+    return await crypto.subtle.decrypt(
+        {
+            name: 'AES-GCM',
+            iv: bytes.slice(0, 12),
+        },
+        key,
+        bytes.slice(12)
+    );
+}